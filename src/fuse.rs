@@ -0,0 +1,221 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    io,
+    time::{Duration, SystemTime},
+};
+
+use fuser::{
+    FileAttr, FileType as FuseFileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEntry, Request,
+};
+
+use crate::{
+    hardware::storage::block::BLOCK_SIZE,
+    kernel::{
+        Kernel,
+        file::{AtimeMode, FileStats, SortOrder},
+        fs::{node::FileType, transaction},
+        syscall,
+    },
+};
+
+/// How long the kernel may cache replies.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Inode number FUSE uses for the root directory.
+/// It matches the id of the root node.
+const ROOT_INO: u64 = 1;
+
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+
+/// A read-only FUSE adapter over the [Kernel] syscalls.
+///
+/// Inode numbers are node ids. The syscalls are path-based,
+/// so the adapter remembers the path each inode was looked up by.
+pub struct FuseAdapter {
+    kernel: Kernel,
+    paths: BTreeMap<u64, String>,
+}
+
+impl FuseAdapter {
+    /// Constructs a [FuseAdapter] over a kernel with a mounted filesystem.
+    /// Reads don't update access times, as the adapter is read-only.
+    pub fn new(mut kernel: Kernel) -> Self {
+        kernel.set_atime_mode(AtimeMode::Never);
+        Self {
+            kernel,
+            paths: BTreeMap::from([(ROOT_INO, String::from("/"))]),
+        }
+    }
+
+    /// Returns the path of a previously looked up inode.
+    fn path(&self, ino: u64) -> Result<String, i32> {
+        self.paths.get(&ino).cloned().ok_or(ENOENT)
+    }
+
+    /// Returns the path of the entry `name` inside the directory at `parent`.
+    fn child_path(parent: &str, name: &OsStr) -> Result<String, i32> {
+        let name = name.to_str().ok_or(ENOENT)?;
+        Ok(match parent {
+            "/" => format!("/{}", name),
+            _ => format!("{}/{}", parent, name),
+        })
+    }
+
+    /// Returns the attributes of the file at `path`, without following symlinks.
+    fn attr(&mut self, path: &str) -> Result<FileAttr, i32> {
+        let stats = self.kernel.lstat(path).map_err(errno)?;
+        Ok(to_attr(&stats))
+    }
+
+    /// Reads up to `size` bytes from `offset` of the file at `path`.
+    fn read_at(&mut self, path: &str, offset: usize, size: usize) -> Result<Vec<u8>, i32> {
+        let fd = self.kernel.open(path).map_err(errno)?;
+        let mut buf = vec![0u8; size];
+        let result = self
+            .kernel
+            .seek(fd, offset)
+            .and_then(|_| self.kernel.read(fd, &mut buf));
+        self.kernel.close(fd).map_err(errno)?;
+        buf.truncate(result.map_err(errno)?);
+        Ok(buf)
+    }
+}
+
+impl Filesystem for FuseAdapter {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let result = self
+            .path(parent)
+            .and_then(|p| Self::child_path(&p, name))
+            .and_then(|path| {
+                let attr = self.attr(&path)?;
+                self.paths.insert(attr.ino, path);
+                Ok(attr)
+            });
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.path(ino).and_then(|path| self.attr(&path)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let result = self
+            .path(ino)
+            .and_then(|path| self.read_at(&path, offset as usize, size as usize));
+        match result {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let result = self.path(ino).and_then(|path| {
+            let entries = self
+                .kernel
+                .ls_long(&path, true, SortOrder::default())
+                .map_err(errno)?;
+            Ok((path, entries))
+        });
+        let (path, entries) = match result {
+            Ok(result) => result,
+            Err(e) => return reply.error(e),
+        };
+        for (i, (name, stats)) in entries.iter().enumerate().skip(offset as usize) {
+            if name != "." && name != ".." {
+                let child = Self::child_path(&path, OsStr::new(name)).unwrap_or_default();
+                self.paths.entry(stats.node_id as u64).or_insert(child);
+            }
+            // The offset passed back is that of the next entry
+            if reply.add(
+                stats.node_id as u64,
+                (i + 1) as i64,
+                to_kind(stats.filetype),
+                name,
+            ) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mounts the kernel's filesystem read-only at `mountpoint`, blocking until it is unmounted.
+pub fn mount(kernel: Kernel, mountpoint: impl AsRef<std::path::Path>) -> io::Result<()> {
+    let options = [MountOption::RO, MountOption::FSName(String::from("os_lab"))];
+    fuser::mount2(FuseAdapter::new(kernel), mountpoint, &options)
+}
+
+/// Converts file statistics into FUSE attributes.
+/// The filesystem keeps only access and modification times and no owners,
+/// so fixed ones fill in the rest.
+fn to_attr(stats: &FileStats) -> FileAttr {
+    let atime = SystemTime::UNIX_EPOCH + Duration::from_secs(stats.atime);
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(stats.mtime);
+    let perm = match stats.filetype {
+        FileType::Dir => 0o555,
+        FileType::File | FileType::Symlink => 0o444,
+    };
+    FileAttr {
+        ino: stats.node_id as u64,
+        size: stats.size as u64,
+        blocks: (stats.block_count * BLOCK_SIZE / 512) as u64,
+        atime,
+        mtime,
+        ctime: mtime,
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: to_kind(stats.filetype),
+        perm,
+        nlink: stats.link_count,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: BLOCK_SIZE as u32,
+        flags: 0,
+    }
+}
+
+fn to_kind(filetype: FileType) -> FuseFileType {
+    match filetype {
+        FileType::File => FuseFileType::RegularFile,
+        FileType::Dir => FuseFileType::Directory,
+        FileType::Symlink => FuseFileType::Symlink,
+    }
+}
+
+/// Maps a syscall error to an errno value.
+fn errno(error: syscall::Error) -> i32 {
+    match error {
+        syscall::Error::Filesystem(transaction::Error::NodeNotFound) => ENOENT,
+        syscall::Error::Filesystem(transaction::Error::NotDir) | syscall::Error::NotDir => ENOTDIR,
+        syscall::Error::Filesystem(transaction::Error::IsDir) => EISDIR,
+        _ => EIO,
+    }
+}
@@ -0,0 +1,67 @@
+use std::{fs, io, path::Path};
+
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use super::{
+    BlockDevice, Storage,
+    block::{BLOCK_SIZE, Block},
+};
+
+/// A magic number to identify storage images.
+pub const IMAGE_MAGIC: [u8; 8] = *b"FSIMAGE\0";
+
+/// Describes the contents of a storage image.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct Header {
+    magic: [u8; 8],
+    block_size: u64,
+    block_count: u64,
+}
+
+/// Writes every block of the device into an image file at `path`.
+pub fn save(device: &dyn BlockDevice, path: &Path) -> Result<()> {
+    let block_count = device.block_count();
+    let header = Header {
+        magic: IMAGE_MAGIC,
+        block_size: BLOCK_SIZE as u64,
+        block_count: block_count as u64,
+    };
+    let mut bytes = Vec::with_capacity(size_of::<Header>() + block_count * BLOCK_SIZE);
+    bytes.extend_from_slice(header.as_bytes());
+    for id in 0..block_count {
+        let block = device.read_block(id).map_err(Error::Storage)?;
+        bytes.extend_from_slice(block.as_bytes());
+    }
+    fs::write(path, bytes).map_err(Error::Io)
+}
+
+/// Reads an image file at `path` into a [Storage].
+pub fn load(path: &Path) -> Result<Storage> {
+    let bytes = fs::read(path).map_err(Error::Io)?;
+    let (header, data) = Header::read_from_prefix(&bytes).map_err(|_| Error::Truncated)?;
+    if header.magic != IMAGE_MAGIC {
+        return Err(Error::InvalidMagic);
+    }
+    if header.block_size != BLOCK_SIZE as u64 {
+        return Err(Error::BlockSizeMismatch);
+    }
+    // The header is untrusted, so a block count too large to hold is treated as truncated data
+    let expected_len = header.block_count.checked_mul(BLOCK_SIZE as u64);
+    if expected_len != Some(data.len() as u64) {
+        return Err(Error::Truncated);
+    }
+    let blocks = Block::slice_from_bytes(data);
+    Ok(Storage::from_blocks(Box::from(blocks)))
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Storage(super::Error),
+    InvalidMagic,
+    BlockSizeMismatch,
+    Truncated,
+}
@@ -1,10 +1,79 @@
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::BTreeSet,
+};
+
 use block::*;
 
 pub mod block;
+pub mod image;
+
+/// A device that stores data in fixed-size blocks.
+pub trait BlockDevice {
+    /// Returns the number of blocks on the device.
+    fn block_count(&self) -> usize;
+
+    /// Returns the copy of a persistent block at `id`.
+    fn read_block(&self, id: usize) -> Result<Block>;
+
+    /// Returns the persistent block at `id`, borrowed if the device keeps it in memory.
+    /// By default, the block is copied like in [BlockDevice::read_block].
+    fn read_block_ref(&self, id: usize) -> Result<Cow<'_, Block>> {
+        self.read_block(id).map(Cow::Owned)
+    }
+
+    /// Returns a vector of copies of persistent blocks at `ids`.
+    fn read_blocks(&self, ids: &[usize]) -> Result<Box<[Block]>> {
+        let mut blocks = Vec::with_capacity(ids.len());
+        for &i in ids {
+            blocks.push(self.read_block(i)?);
+        }
+        Ok(blocks.into_boxed_slice())
+    }
+
+    /// Writes data from the `src` block into the persistent block at `id`.
+    fn write_block(&mut self, id: usize, src: &Block) -> Result<()>;
+
+    /// Writes data from the 'srcs' blocks into persistent blocks at `ids`.
+    ///
+    /// # Panics
+    /// Panics if:
+    /// - lengths of `srcs` and `ids` are mismatched
+    fn write_blocks(&mut self, ids: &[usize], srcs: &[Block]) -> Result<()> {
+        assert!(
+            srcs.len() == ids.len(),
+            "Length of 'srcs' {} does not equal to length of 'ids' {}",
+            srcs.len(),
+            ids.len()
+        );
+        for (src, &i) in srcs.iter().zip(ids.iter()) {
+            self.write_block(i, src)?
+        }
+        Ok(())
+    }
 
-/// A model of a blocked physical storage device.
+    /// Grows the device to `new_size` bytes, preserving existing blocks.
+    ///
+    /// # Errors
+    /// Returns `Err` if:
+    /// - `new_size` is not a multiple of [BLOCK_SIZE]
+    /// - `new_size` is smaller than the current size
+    /// - the device can't be resized
+    fn grow(&mut self, new_size: usize) -> Result<()>;
+}
+
+/// An in-memory model of a blocked physical storage device.
+///
+/// Access latency is simulated by counting nanoseconds rather than sleeping.
 pub struct Storage {
     blocks: Box<[Block]>,
+    /// Simulated time each block access takes, in nanoseconds.
+    latency: u64,
+    /// Total simulated time spent on block accesses, in nanoseconds.
+    elapsed: Cell<u64>,
+    /// Ids of blocks whose next access fails.
+    injected: RefCell<BTreeSet<usize>>,
 }
 
 impl Storage {
@@ -17,51 +86,91 @@ impl Storage {
         assert!(size.is_multiple_of(BLOCK_SIZE));
         let block_count = size / BLOCK_SIZE;
         let blocks = vec![Block::default(); block_count].into_boxed_slice();
-        Self { blocks }
+        Self::from_blocks(blocks)
+    }
+
+    /// Constructs a [Storage] holding `blocks`.
+    fn from_blocks(blocks: Box<[Block]>) -> Self {
+        Self {
+            blocks,
+            latency: 0,
+            elapsed: Cell::new(0),
+            injected: RefCell::new(BTreeSet::new()),
+        }
     }
 
-    pub fn block_count(&self) -> usize {
+    /// Sets the simulated time each block access takes, in nanoseconds.
+    pub fn set_latency(&mut self, ns: u64) {
+        self.latency = ns;
+    }
+
+    /// Returns the total simulated time spent on block accesses, in nanoseconds.
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed.get()
+    }
+
+    /// Makes the next read or write of the block at `id` fail with [Error::Injected].
+    pub fn inject_error(&mut self, id: usize) {
+        self.injected.borrow_mut().insert(id);
+    }
+
+    /// Accounts for an access to the block at `id`, consuming an injected error if there is one.
+    fn access(&self, id: usize) -> Result<()> {
+        self.elapsed.set(self.elapsed.get() + self.latency);
+        if self.injected.borrow_mut().remove(&id) {
+            return Err(Error::Injected);
+        }
+        Ok(())
+    }
+
+    /// Saves the whole storage into a self-describing image file at `path`.
+    pub fn save_image(&self, path: impl AsRef<std::path::Path>) -> image::Result<()> {
+        image::save(self, path.as_ref())
+    }
+
+    /// Loads a [Storage] from an image file at `path`.
+    pub fn load_image(path: impl AsRef<std::path::Path>) -> image::Result<Self> {
+        image::load(path.as_ref())
+    }
+}
+
+impl BlockDevice for Storage {
+    fn block_count(&self) -> usize {
         self.blocks.len()
     }
 
-    /// Returns the copy of a persistent block at `id`.
-    pub fn read_block(&self, id: usize) -> Result<Block> {
+    fn read_block(&self, id: usize) -> Result<Block> {
+        self.access(id)?;
         let block = self.blocks.get(id).ok_or(Error::BlockIdOutOfBounds)?;
         Ok(*block)
     }
 
-    /// Returns a vector of copies of persistent blocks at `ids`.
-    pub fn read_blocks(&self, ids: &[usize]) -> Result<Box<[Block]>> {
-        let mut blocks = Vec::with_capacity(ids.len());
-        for &i in ids {
-            let block = self.blocks.get(i).ok_or(Error::BlockIdOutOfBounds)?;
-            blocks.push(*block);
-        }
-        Ok(blocks.into_boxed_slice())
+    fn read_block_ref(&self, id: usize) -> Result<Cow<'_, Block>> {
+        self.access(id)?;
+        let block = self.blocks.get(id).ok_or(Error::BlockIdOutOfBounds)?;
+        Ok(Cow::Borrowed(block))
     }
 
-    /// Writes data from the `src` block into the persistent block at `id`.
-    pub fn write_block(&mut self, id: usize, src: &Block) -> Result<()> {
+    fn write_block(&mut self, id: usize, src: &Block) -> Result<()> {
+        self.access(id)?;
         let dst = self.blocks.get_mut(id).ok_or(Error::BlockIdOutOfBounds)?;
         *dst = *src;
         Ok(())
     }
 
-    /// Writes data from the 'srcs' blocks into persistent blocks at `ids`.
-    ///
-    /// # Panics
-    /// Panics if:
-    /// - lengths of `srcs` and `ids` are mismatched
-    pub fn write_blocks(&mut self, ids: &[usize], srcs: &[Block]) -> Result<()> {
-        assert!(
-            srcs.len() == ids.len(),
-            "Length of 'srcs' {} does not equal to length of 'ids' {}",
-            srcs.len(),
-            ids.len()
-        );
-        for (src, &i) in srcs.iter().zip(ids.iter()) {
-            self.write_block(i, src)?
+    /// Grows the storage to `new_size` bytes, preserving existing blocks.
+    /// New blocks are zero-initialized.
+    fn grow(&mut self, new_size: usize) -> Result<()> {
+        if !new_size.is_multiple_of(BLOCK_SIZE) {
+            return Err(Error::InvalidSize);
+        }
+        let block_count = new_size / BLOCK_SIZE;
+        if block_count < self.blocks.len() {
+            return Err(Error::CannotShrink);
         }
+        let mut blocks = std::mem::take(&mut self.blocks).into_vec();
+        blocks.resize(block_count, Block::default());
+        self.blocks = blocks.into_boxed_slice();
         Ok(())
     }
 }
@@ -71,4 +180,7 @@ type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     BlockIdOutOfBounds,
+    InvalidSize,
+    CannotShrink,
+    Injected,
 }
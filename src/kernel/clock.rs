@@ -0,0 +1,48 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of the current time, in seconds since the Unix epoch.
+pub trait Clock {
+    /// Returns the current time.
+    fn now(&self) -> u64;
+
+    /// Returns the name of the time source.
+    fn name(&self) -> &'static str;
+}
+
+/// A clock backed by the host system time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    fn name(&self) -> &'static str {
+        "system"
+    }
+}
+
+/// A clock that only moves when told to, for deterministic timestamps.
+pub struct ManualClock {
+    time: u64,
+}
+
+impl ManualClock {
+    /// Constructs a [ManualClock] stopped at `time`.
+    pub fn new(time: u64) -> Self {
+        Self { time }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.time
+    }
+
+    fn name(&self) -> &'static str {
+        "manual"
+    }
+}
@@ -1,6 +1,12 @@
 use std::collections::BTreeMap;
 
-use crate::kernel::fs::node::{FileType, Node, NodePtr};
+use crate::{
+    hardware::storage::block::BLOCK_SIZE,
+    kernel::fs::{
+        node::{FileType, Node, NodePtr},
+        transaction::Reservation,
+    },
+};
 
 /// Tracks opened files.
 pub type OpenFileTable = BTreeMap<FileDescriptor, FileDescription>;
@@ -8,24 +14,93 @@ pub type OpenFileTable = BTreeMap<FileDescriptor, FileDescription>;
 /// A unique id used to track opened files.
 pub type FileDescriptor = usize;
 
+/// How many descriptors are set aside for standard streams when they are reserved.
+pub const STD_FD_COUNT: FileDescriptor = 3;
+
 /// A unique handle to a file.
 pub struct FileDescription {
     node_ptr: NodePtr,
     pub offset: usize,
+    pub reservation: Reservation,
+    /// How many bytes were read through the description.
+    pub bytes_read: usize,
+    /// How many bytes were written through the description.
+    pub bytes_written: usize,
 }
 
 impl FileDescription {
     /// Creates a new [FileDescriptor] for the file.
-    pub fn new(node_ptr: NodePtr) -> Self {
+    pub fn new(node_ptr: NodePtr, options: OpenOptions) -> Self {
         Self {
             node_ptr,
             offset: 0,
+            reservation: Reservation::new(options.preferred_extent),
+            bytes_read: 0,
+            bytes_written: 0,
         }
     }
 
     pub fn node_ptr(&self) -> NodePtr {
         self.node_ptr
     }
+
+    pub fn set_node_ptr(&mut self, node_ptr: NodePtr) {
+        self.node_ptr = node_ptr;
+    }
+}
+
+/// Options that control how a file is opened.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OpenOptions {
+    /// How many contiguous blocks to reserve on an allocating write.
+    /// Values below 2 disable the reservation.
+    pub preferred_extent: usize,
+    /// Whether a missing file is created, if its parent directory exists.
+    pub create: bool,
+    /// Whether opening fails if the file already exists. Only applies with `create`.
+    pub exclusive: bool,
+}
+
+/// Options that control how the storage is formatted.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MkfsOptions {
+    /// Whether to format even if files are open, closing them.
+    pub force: bool,
+    /// Whether to zero every block of the data region.
+    pub zero_fill: bool,
+}
+
+/// How long an access time stays fresh under [AtimeMode::Relative], in seconds.
+pub const RELATIME_INTERVAL: u64 = 24 * 60 * 60;
+
+/// When reads update the access time of a file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AtimeMode {
+    /// On every read.
+    Strict,
+    /// Only if the access time isn't newer than the modification time
+    /// or is older than [RELATIME_INTERVAL].
+    #[default]
+    Relative,
+    /// Never.
+    Never,
+}
+
+/// What directory listings are sorted by.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Node,
+}
+
+/// How directory listings are ordered.
+/// Entries that tie on the key are ordered by name.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SortOrder {
+    pub key: SortKey,
+    pub descending: bool,
 }
 
 pub struct FileStats {
@@ -34,6 +109,13 @@ pub struct FileStats {
     pub link_count: u32,
     pub size: usize,
     pub block_count: usize,
+    pub extent_count: usize,
+    /// Whether the contents are stored inline in the node.
+    pub inline: bool,
+    /// Time of the last modification, in seconds since the Unix epoch.
+    pub mtime: u64,
+    /// Time of the last access, in seconds since the Unix epoch.
+    pub atime: u64,
 }
 
 impl FileStats {
@@ -44,6 +126,116 @@ impl FileStats {
             link_count: node.link_count,
             size: node.size,
             block_count: node.block_count(),
+            extent_count: node.get_extents().iter().filter(|e| !e.is_null()).count(),
+            inline: node.is_inline(),
+            mtime: node.mtime(),
+            atime: node.atime(),
+        }
+    }
+}
+
+/// Describes a directory entry along with the node it references.
+pub struct DirEntryInfo {
+    pub name: String,
+    pub node_id: usize,
+    pub filetype: FileType,
+    pub size: usize,
+    pub link_count: u32,
+}
+
+/// Describes where a byte range of a file is stored.
+pub struct ExtentMapping {
+    pub logical_offset: usize,
+    pub length: usize,
+    /// Id of the block holding the first byte of the range, zero for a hole or inline data.
+    pub physical_block: usize,
+    pub is_hole: bool,
+    /// Whether the range is stored inline in the node rather than in data blocks.
+    pub inline: bool,
+}
+
+impl ExtentMapping {
+    /// Maps the bytes of the node in `[offset, offset + len)`, cut off at the end of the node,
+    /// one mapping per extent the range touches, in file order.
+    /// Contents stored inline in the node are a single mapping flagged as `inline`.
+    pub fn map_node(node: &Node, offset: usize, len: usize) -> Vec<Self> {
+        let end = offset.saturating_add(len).min(node.size);
+        let mut mappings = Vec::new();
+        if offset >= end {
+            return mappings;
+        }
+        if node.is_inline() {
+            mappings.push(Self {
+                logical_offset: offset,
+                length: end - offset,
+                physical_block: 0,
+                is_hole: false,
+                inline: true,
+            });
+            return mappings;
+        }
+
+        let mut pos = 0;
+        for extent in node.get_extents().iter().take_while(|e| !e.is_null()) {
+            let extent_end = pos + extent.len() * BLOCK_SIZE;
+            let (lo, hi) = (offset.max(pos), end.min(extent_end));
+            if lo < hi {
+                let physical_block = if extent.is_hole() {
+                    0
+                } else {
+                    extent.start() + (lo - pos) / BLOCK_SIZE
+                };
+                mappings.push(Self {
+                    logical_offset: lo,
+                    length: hi - lo,
+                    physical_block,
+                    is_hole: extent.is_hole(),
+                    inline: false,
+                });
+            }
+            pos = extent_end;
         }
+        // A file grown past its last extent reads as a hole there
+        let lo = offset.max(pos);
+        if lo < end {
+            mappings.push(Self {
+                logical_offset: lo,
+                length: end - lo,
+                physical_block: 0,
+                is_hole: true,
+                inline: false,
+            });
+        }
+        mappings
     }
 }
+
+/// Describes the I/O done through an open file description.
+pub struct FdStats {
+    pub node_id: usize,
+    pub offset: usize,
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+}
+
+/// Describes what the tree reachable from the root holds.
+/// A node with several hard links is counted once.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TreeSummary {
+    pub files: usize,
+    /// Directories, including the root.
+    pub dirs: usize,
+    pub symlinks: usize,
+    /// Sum of the sizes of every node.
+    pub bytes: usize,
+    /// Sum of the data blocks of every node.
+    pub blocks: usize,
+}
+
+/// Describes the results of a filesystem check.
+pub struct FsckReport {
+    /// Ids of allocated nodes that no directory entry references.
+    pub orphans: Vec<usize>,
+    /// Ids of orphans that were linked into `lost+found`.
+    pub recovered: Vec<usize>,
+}
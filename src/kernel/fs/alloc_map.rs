@@ -1,8 +1,13 @@
 use zerocopy::{Immutable, IntoBytes, TryFromBytes};
 
 /// Tracks allocation state of objects.
+#[derive(Clone)]
 pub struct AllocMap {
     flags: Box<[AllocFlag]>,
+    strict: bool,
+    strategy: Strategy,
+    /// Where the next search starts under [Strategy::Spread], just past the last allocation.
+    cursor: usize,
 }
 
 impl AllocMap {
@@ -10,18 +15,41 @@ impl AllocMap {
     pub fn new(count: usize) -> Self {
         AllocMap {
             flags: vec![AllocFlag::default(); count].into_boxed_slice(),
+            strict: false,
+            strategy: Strategy::default(),
+            cursor: 0,
         }
     }
 
-    // NOTE: Explore using the Next-fit algorithm
+    /// Checks whether freeing an already free object is an error.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Sets whether freeing an already free object is an error.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Returns the allocation strategy.
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Sets the allocation strategy.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
     /// Tries to find a contiguous span of free objects of `count` length, using the First-fit algorithm.
+    /// The search starts at `from`, so spans below it are not considered.
     /// On success, returns a (start, end) tuple, representing an exclusive range of ids.
-    fn find_free(&self, count: usize) -> Option<(usize, usize)> {
+    fn find_free(&self, count: usize, from: usize) -> Option<(usize, usize)> {
         if count == 0 {
             return None;
         }
-        let mut start = 0;
-        for (i, flag) in self.flags.iter().enumerate() {
+        let mut start = from;
+        for (i, flag) in self.flags.iter().enumerate().skip(from) {
             if *flag == AllocFlag::Used {
                 start = i + 1;
                 continue;
@@ -35,14 +63,43 @@ impl AllocMap {
 
     /// Tries to allocate a contiguous span of objects of `count` length.
     /// On success, returns a (start, end) tuple, representing an exclusive range of ids.
+    ///
+    /// Under [Strategy::FirstFit], the lowest-starting fitting span is always chosen, so allocation
+    /// order is deterministic: a single object is always allocated at the lowest free id.
+    /// Under [Strategy::Spread], the search starts at a cursor that moves past each allocation,
+    /// wrapping around to the start of the map.
     pub fn allocate(&mut self, count: usize) -> Result<(usize, usize)> {
-        let span = self.find_free(count).ok_or(Error::OutOfSpace)?;
+        let span = match self.strategy {
+            Strategy::FirstFit => self.find_free(count, 0),
+            Strategy::Spread => self
+                .find_free(count, self.cursor)
+                .or_else(|| self.find_free(count, 0)),
+        }
+        .ok_or(Error::OutOfSpace)?;
         for flag in &mut self.flags[span.0..span.1] {
             *flag = AllocFlag::Used;
         }
+        self.cursor = span.1 % self.flags.len();
         Ok(span)
     }
 
+    /// Tries to allocate a single object as close to `hint` as possible,
+    /// searching outward in both directions and preferring ids above `hint` at equal distance.
+    /// Falls back to any free object when `hint` is out of bounds.
+    /// On success, returns the id of the object.
+    pub fn allocate_near(&mut self, hint: usize) -> Result<usize> {
+        let count = self.flags.len();
+        let hint = hint.min(count.saturating_sub(1));
+        let id = (0..count)
+            .flat_map(|d| [hint.checked_add(d), hint.checked_sub(d)])
+            .flatten()
+            .find(|&id| self.flags.get(id) == Some(&AllocFlag::Free))
+            .ok_or(Error::OutOfSpace)?;
+        self.flags[id] = AllocFlag::Used;
+        self.cursor = (id + 1) % count;
+        Ok(id)
+    }
+
     /// Tries to allocate the object at `id`.
     pub fn allocate_at(&mut self, id: usize) -> Result<()> {
         let flag = self.flags.get_mut(id).ok_or(Error::IdOutOfBounds)?;
@@ -73,6 +130,10 @@ impl AllocMap {
 
     /// Marks the span of objects as free.
     ///
+    /// # Errors
+    /// Returns `Err` if:
+    /// - the map is strict and the span contains an already free object
+    ///
     /// # Panics
     /// Panics if:
     /// - `span` is not a valid span
@@ -82,10 +143,72 @@ impl AllocMap {
             .flags
             .get_mut(id_span.0..id_span.1)
             .ok_or(Error::IdOutOfBounds)?;
+        if self.strict && span.contains(&AllocFlag::Free) {
+            return Err(Error::DoubleFree);
+        }
         span.fill(AllocFlag::Free);
         Ok(())
     }
 
+    /// Extends the map to represent `count` objects, marking the new ones as free.
+    ///
+    /// # Panics
+    /// Panics if:
+    /// - `count` is smaller than the current count
+    pub fn grow(&mut self, count: usize) {
+        assert!(count >= self.flags.len());
+        let mut flags = std::mem::take(&mut self.flags).into_vec();
+        flags.resize(count, AllocFlag::Free);
+        self.flags = flags.into_boxed_slice();
+    }
+
+    /// Checks whether the object at `id` is allocated.
+    pub fn is_used(&self, id: usize) -> bool {
+        self.flags.get(id) == Some(&AllocFlag::Used)
+    }
+
+    /// Returns an iterator over ids of allocated objects.
+    pub fn used_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.flags
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| **f == AllocFlag::Used)
+            .map(|(i, _)| i)
+    }
+
+    /// Splits the map into runs of objects that share the same state.
+    /// Returns a (flag, start, len) tuple for each run, in id order.
+    pub fn runs(&self) -> Vec<(AllocFlag, usize, usize)> {
+        let mut runs: Vec<(AllocFlag, usize, usize)> = Vec::new();
+        for (i, &flag) in self.flags.iter().enumerate() {
+            match runs.last_mut() {
+                Some((last, _, len)) if *last == flag => *len += 1,
+                _ => runs.push((flag, i, 1)),
+            }
+        }
+        runs
+    }
+
+    /// Compares the map against an `older` version of it.
+    /// Returns a (allocated, freed) tuple with the number of objects that changed state since.
+    pub fn diff(&self, older: &AllocMap) -> (usize, usize) {
+        let mut allocated = 0;
+        let mut freed = 0;
+        for (new, old) in self.flags.iter().zip(older.flags.iter()) {
+            match (new, old) {
+                (AllocFlag::Used, AllocFlag::Free) => allocated += 1,
+                (AllocFlag::Free, AllocFlag::Used) => freed += 1,
+                _ => (),
+            }
+        }
+        // Objects beyond the older map's end are new
+        allocated += self.flags[older.flags.len().min(self.flags.len())..]
+            .iter()
+            .filter(|f| **f == AllocFlag::Used)
+            .count();
+        (allocated, freed)
+    }
+
     /// Returns a view of the allocation map as a slice of [AllocFlag].
     pub fn as_slice(&self) -> &[AllocFlag] {
         &self.flags
@@ -95,12 +218,15 @@ impl AllocMap {
     pub fn from_slice(flags: &[AllocFlag]) -> Self {
         Self {
             flags: Box::from(flags),
+            strict: false,
+            strategy: Strategy::default(),
+            cursor: 0,
         }
     }
 }
 
 /// Represents allocation state of an object.
-#[derive(Default, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 #[derive(TryFromBytes, IntoBytes, Immutable)]
 #[repr(u8)]
 pub enum AllocFlag {
@@ -109,6 +235,16 @@ pub enum AllocFlag {
     Used,
 }
 
+/// Describes where [AllocMap::allocate] looks for free objects.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Always takes the lowest fitting span.
+    #[default]
+    FirstFit,
+    /// Continues from the end of the previous allocation, spreading objects across the map.
+    Spread,
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -116,4 +252,5 @@ pub enum Error {
     IdOutOfBounds,
     ObjectOccupied,
     OutOfSpace,
+    DoubleFree,
 }
@@ -30,12 +30,25 @@ impl Dir {
             .find(|e| e.name == name && !e.is_null())
     }
 
-    /// Adds an entry to the directory.
-    pub fn add_entry(&mut self, entry: DirEntry) {
-        let vacancy = self.entries.iter_mut().find(|e| e.is_null());
+    /// Returns the index of the slot holding the entry with given name.
+    pub fn get_slot(&self, name: DirEntryName) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|e| e.name == name && !e.is_null())
+    }
+
+    /// Adds an entry to the directory, returning the index of the slot it took.
+    pub fn add_entry(&mut self, entry: DirEntry) -> usize {
+        let vacancy = self.entries.iter().position(|e| e.is_null());
         match vacancy {
-            Some(v) => *v = entry,
-            None => self.entries.push(entry),
+            Some(slot) => {
+                self.entries[slot] = entry;
+                slot
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
         }
     }
 
@@ -47,6 +60,22 @@ impl Dir {
         Ok(node_ptr)
     }
 
+    /// Points the `..` entry of the directory at `parent_ptr`.
+    pub fn set_parent(&mut self, parent_ptr: NodePtr) {
+        let parent = DirEntry::parent(parent_ptr);
+        match self.get_mut_entry(parent.name) {
+            Some(entry) => *entry = parent,
+            None => {
+                self.add_entry(parent);
+            }
+        }
+    }
+
+    /// Removes null entries, shrinking the directory to its live entries.
+    pub fn compact(&mut self) {
+        self.entries.retain(|e| !e.is_null());
+    }
+
     /// Checks if the directory is empty (contains only `.` and `..` entries).
     pub fn is_empty(&self) -> bool {
         self.entries.iter().filter(|e| !e.is_null()).count() == 2
@@ -57,6 +86,11 @@ impl Dir {
         self.entries.as_slice()
     }
 
+    /// Returns a view of the directory as a mutable slice of [DirEntry].
+    pub fn as_mut_slice(&mut self) -> &mut [DirEntry] {
+        self.entries.as_mut_slice()
+    }
+
     /// Constructs a [Dir] from a slice of [DirEntry].
     pub fn from_slice(entries: &[DirEntry]) -> Self {
         Self {
@@ -118,6 +152,10 @@ impl DirEntry {
         self.node_ptr
     }
 
+    pub fn set_node_ptr(&mut self, node_ptr: NodePtr) {
+        self.node_ptr = node_ptr;
+    }
+
     pub fn name(&self) -> Result<&str> {
         self.name.as_str()
     }
@@ -135,6 +173,14 @@ pub struct DirEntryName {
 }
 
 impl DirEntryName {
+    /// How long a name can be, in bytes.
+    pub const MAX_LEN: usize = NAME_MAX;
+
+    /// Checks whether `name` fits into a [DirEntryName].
+    pub fn is_valid(name: &str) -> bool {
+        name.len() <= Self::MAX_LEN
+    }
+
     /// Returns the directory entry name as a string slice `&str`.
     ///
     /// # Errors
@@ -149,10 +195,10 @@ impl TryFrom<&str> for DirEntryName {
     type Error = Error;
 
     fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-        let len = value.len();
-        if len > NAME_MAX {
+        if !Self::is_valid(value) {
             return Err(Error::NameTooLong);
         }
+        let len = value.len();
         let mut bytes = [0u8; NAME_MAX];
         bytes[..len].copy_from_slice(value.as_bytes());
         Ok(Self { bytes })
@@ -1,13 +1,13 @@
 use zerocopy::{FromBytes, IntoBytes, TryFromBytes};
 
 use crate::{
-    hardware::storage::{Storage, block::Block},
+    hardware::storage::{BlockDevice, block::Block},
     kernel::fs::{
-        alloc_map::{AllocFlag, AllocMap},
+        alloc_map::{AllocFlag, AllocMap, Strategy},
         directory::Dir,
         node::{FileType, NodePtr},
         superblock::Superblock,
-        transaction::Transaction,
+        transaction::{CommitError, CommitRecord, CrashPoint, Transaction},
     },
 };
 
@@ -17,22 +17,48 @@ pub mod node;
 pub mod path;
 pub mod superblock;
 pub mod transaction;
+pub mod xattr;
+
+/// How many blocks a transaction buffers by default before flushing new data blocks.
+pub const FLUSH_LIMIT: usize = 1024;
+
+/// A callback invoked with the effects of every committed transaction.
+pub type CommitHook = Box<dyn FnMut(&CommitRecord)>;
 
 /// An in-memory view of the filesystem.
 pub struct Filesystem {
     superblock: Superblock,
     block_map: AllocMap,
     node_map: AllocMap,
+    commit_hook: Option<CommitHook>,
+    flush_limit: usize,
+    write_through: bool,
+    crash_point: Option<CrashPoint>,
+    /// Set once a simulated power loss cut a commit short. Nothing reaches the storage afterwards.
+    crashed: bool,
+    /// Set while transactions are aborted rather than committed, so none may write early.
+    dry_run: bool,
 }
 
 impl Filesystem {
     /// Formats the persistent storage with a filesystem.
+    /// If `zero_fill` is set, every block of the data region is zeroed as well.
+    ///
+    /// # Errors
+    /// Returns `Err` if the storage fails to read or write the blocks of the filesystem.
     ///
     /// # Panics
     /// ...
-    pub fn format(storage: &mut Storage, block_count: usize, node_count: usize) -> Self {
+    pub fn format(
+        storage: &mut dyn BlockDevice,
+        block_count: usize,
+        node_count: usize,
+        zero_fill: bool,
+    ) -> Result<Self, Error> {
         // Superblock
-        let superblock = Superblock::new(block_count, node_count);
+        let mut superblock = Superblock::new(block_count, node_count);
+        superblock.uuid = superblock::random_uuid();
+        superblock.update_checksum();
 
         // Allocation maps
         let mut block_map = AllocMap::new(block_count);
@@ -43,6 +69,13 @@ impl Filesystem {
             .allocate_span((0, superblock.data_start))
             .expect("'0..superblock.data_start' blocks must not be allocated");
 
+        // Allocate the backup superblock
+        if let Some(backup_id) = superblock.backup_id() {
+            block_map
+                .allocate_at(backup_id)
+                .expect("Backup superblock block must not be allocated");
+        }
+
         // Allocate the null node
         node_map
             .allocate_at(0)
@@ -53,54 +86,95 @@ impl Filesystem {
             superblock,
             block_map,
             node_map,
+            commit_hook: None,
+            flush_limit: FLUSH_LIMIT,
+            write_through: false,
+            crash_point: None,
+            crashed: false,
+            dry_run: false,
         };
 
         {
-            // Write superblock
-            let superblock = Block::from(&fs.superblock);
+            let data_start = fs.superblock.data_start;
             let mut tx = Transaction::new(&mut fs, storage);
-            tx.write_block(superblock::SUPER_ID, &superblock);
+
+            // Zero the data region first, so that the blocks written below replace the zeroes
+            if zero_fill {
+                for block_id in data_start..block_count {
+                    tx.write_block(block_id, &Block::default());
+                }
+            }
+
+            // Write superblock
+            tx.write_superblock();
 
             // Initialize the root directory
-            let (_, root_id) = tx
-                .create_node(FileType::Dir)
-                .expect("Must be able to create the root node");
+            let (_, root_id) = tx.create_node(FileType::Dir)?;
             assert!(root_id == NodePtr::root());
             let root = Dir::new(root_id, root_id);
-            tx.write_directory(root_id, &root)
-                .expect("Must be able to write the root directory");
+            tx.write_directory(root_id, &root)?;
 
-            tx.commit();
+            tx.commit()?;
         }
 
-        fs
+        Ok(fs)
     }
 
     /// Mounts the filesystem from the persistent storage.
+    /// Falls back to the backup superblock in the last block of the filesystem
+    /// if the primary one can't be read or fails its checksum.
     ///
-    /// # Panics
-    /// ...
-    pub fn mount(storage: &Storage) -> Option<Self> {
-        // Read the superblock
-        let blocks = storage
-            .read_block(0)
-            .expect("Must be able to read the superblock");
-        let bytes = blocks.as_bytes();
+    /// # Errors
+    /// Returns `Err` if:
+    /// - neither superblock is valid
+    /// - the allocation maps can't be read or hold invalid flags
+    pub fn mount(storage: &dyn BlockDevice) -> Result<Self, transaction::Error> {
+        let superblock = Self::read_superblock(storage, superblock::SUPER_ID)
+            .or_else(|| Self::find_backup(storage))
+            .ok_or(transaction::Error::InvalidSuperblock)?;
+        Self::mount_with(storage, superblock)
+    }
+
+    /// Finds the backup superblock by scanning down from the end of the storage.
+    /// The storage may have grown since the filesystem was last resized, leaving the backup
+    /// below its end, so the first valid copy that records its own block is taken.
+    fn find_backup(storage: &dyn BlockDevice) -> Option<Superblock> {
+        (superblock::SUPER_ID + 1..storage.block_count())
+            .rev()
+            .find_map(|block_id| {
+                Self::read_superblock(storage, block_id)
+                    .filter(|superblock| superblock.backup_id() == Some(block_id))
+            })
+    }
+
+    /// Reads a superblock from the block at `block_id`, verifying its magic and checksum.
+    fn read_superblock(storage: &dyn BlockDevice, block_id: usize) -> Option<Superblock> {
+        let block = storage.read_block(block_id).ok()?;
+        let bytes = block.as_bytes();
         let superblock = Superblock::read_from_bytes(&bytes[0..size_of::<Superblock>()])
             .expect("'bytes' must be a valid 'Superblock'");
+        (superblock.magic == superblock::MAGIC && superblock.is_checksum_valid())
+            .then_some(superblock)
+    }
 
-        // Verify magic
-        if superblock.magic != superblock::MAGIC {
-            return None;
-        }
-
+    /// Mounts the filesystem from the persistent storage using `superblock`
+    /// instead of the one on disk, to salvage a filesystem whose superblock is corrupt.
+    /// The on-disk superblock is left as is.
+    ///
+    /// # Errors
+    /// Returns `Err` if the allocation maps described by `superblock` can't be read
+    /// or hold invalid flags.
+    pub fn mount_with(
+        storage: &dyn BlockDevice,
+        superblock: Superblock,
+    ) -> Result<Self, transaction::Error> {
         // Read the block allocation map
         let block_map = Self::read_map(
             storage,
             superblock.block_map_start,
             superblock.node_map_start,
             superblock.block_count,
-        );
+        )?;
 
         // Read the node allocation map
         let node_map = Self::read_map(
@@ -108,23 +182,117 @@ impl Filesystem {
             superblock.node_map_start,
             superblock.node_table_start,
             superblock.node_count,
-        );
+        )?;
 
-        Some(Self {
+        Ok(Self {
             superblock,
             block_map,
             node_map,
+            commit_hook: None,
+            flush_limit: FLUSH_LIMIT,
+            write_through: false,
+            crash_point: None,
+            crashed: false,
+            dry_run: false,
         })
     }
 
-    fn read_map(storage: &Storage, map_start: usize, map_end: usize, count: usize) -> AllocMap {
+    /// Returns the label of the filesystem.
+    pub fn label(&self) -> &str {
+        self.superblock.label()
+    }
+
+    /// Returns the UUID of the filesystem in the canonical hyphenated form.
+    pub fn uuid(&self) -> String {
+        self.superblock.uuid_string()
+    }
+
+    /// Sets the callback invoked after every successful commit that changed blocks,
+    /// replacing the previous one.
+    pub fn set_commit_hook(&mut self, hook: Option<CommitHook>) -> Option<CommitHook> {
+        std::mem::replace(&mut self.commit_hook, hook)
+    }
+
+    /// Sets how many blocks a transaction buffers before flushing new data blocks to storage.
+    pub fn set_flush_limit(&mut self, limit: usize) {
+        self.flush_limit = limit;
+    }
+
+    /// Sets whether transactions write blocks straight to storage instead of buffering them.
+    ///
+    /// This gives up atomicity: a failed or aborted transaction leaves its writes in place.
+    /// Allocation maps are still only written on commit.
+    pub fn set_write_through(&mut self, write_through: bool) {
+        self.write_through = write_through;
+    }
+
+    /// Sets whether transactions are going to be aborted instead of committed.
+    /// Their blocks then stay buffered, rather than being flushed early or written through.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Makes the next commit simulate a power loss at `crash_point`.
+    /// Afterwards the filesystem writes nothing more and has to be mounted again.
+    #[cfg(feature = "crash-points")]
+    pub fn set_crash_point(&mut self, crash_point: Option<CrashPoint>) {
+        self.crash_point = crash_point;
+    }
+
+    /// Returns the block allocation map.
+    pub fn block_map(&self) -> &AllocMap {
+        &self.block_map
+    }
+
+    /// Returns the node allocation map.
+    pub fn node_map(&self) -> &AllocMap {
+        &self.node_map
+    }
+
+    /// Sets whether the allocation maps reject freeing already free objects.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.block_map.set_strict(strict);
+        self.node_map.set_strict(strict);
+    }
+
+    /// Sets the strategy used to allocate data blocks.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.block_map.set_strategy(strategy);
+    }
+
+    fn read_map(
+        storage: &dyn BlockDevice,
+        map_start: usize,
+        map_end: usize,
+        count: usize,
+    ) -> Result<AllocMap, transaction::Error> {
         let block_ids: Vec<usize> = (map_start..map_end).collect();
         let blocks = storage
             .read_blocks(&block_ids)
-            .expect("Must be able to read the allocation map");
+            .map_err(transaction::Error::Storage)?;
         let bytes = blocks.as_bytes();
         let flags = <[AllocFlag]>::try_ref_from_bytes(bytes)
-            .expect("'bytes' must be a valid '<[AllocFlag]>'");
-        AllocMap::from_slice(&flags[..count])
+            .map_err(|_| transaction::Error::CorruptedMap)?;
+        let flags = flags.get(..count).ok_or(transaction::Error::CorruptedMap)?;
+        Ok(AllocMap::from_slice(flags))
+    }
+}
+
+/// An error of formatting the storage with a filesystem.
+#[derive(Debug)]
+pub enum Error {
+    Transaction(transaction::Error),
+    Commit(CommitError),
+}
+
+impl From<transaction::Error> for Error {
+    fn from(value: transaction::Error) -> Self {
+        Self::Transaction(value)
+    }
+}
+
+impl From<CommitError> for Error {
+    fn from(value: CommitError) -> Self {
+        Self::Commit(value)
     }
 }
@@ -9,7 +9,13 @@ pub const NODE_SIZE: usize = size_of::<Node>();
 pub const NODES_PER_BLOCK: usize = BLOCK_SIZE / NODE_SIZE;
 
 /// How many extents a [Node] can have.
-const EXTENTS_PER_NODE: usize = 15;
+const EXTENTS_PER_NODE: usize = 14;
+
+/// How many bytes of contents a [Node] can store inline, in the space of its extents.
+pub const INLINE_MAX: usize = EXTENTS_PER_NODE * size_of::<Extent>();
+
+/// Set in [Node]'s flags when its contents are stored inline.
+const FLAG_INLINE: u8 = 1;
 
 /// A pointer to a node.
 #[repr(C)]
@@ -49,7 +55,14 @@ pub struct Node {
     pub size: usize,
     pub link_count: u32,
     filetype: FileType,
-    _pad: [u8; 3],
+    flags: u8,
+    _pad: [u8; 2],
+    /// Id of the block holding extended attributes, 0 if there are none.
+    pub xattr_block: usize,
+    /// Time of the last modification, in seconds since the Unix epoch.
+    mtime: u32,
+    /// Time of the last access, in seconds since the Unix epoch.
+    atime: u32,
     extents: [Extent; EXTENTS_PER_NODE],
 }
 
@@ -67,19 +80,77 @@ impl Node {
         self.filetype
     }
 
-    /// Returns a reference to node's extents.
+    /// Returns the time of the last modification, in seconds since the Unix epoch.
+    pub fn mtime(&self) -> u64 {
+        self.mtime as u64
+    }
+
+    /// Sets the time of the last modification, saturating at the latest storable time.
+    pub fn set_mtime(&mut self, time: u64) {
+        self.mtime = u32::try_from(time).unwrap_or(u32::MAX);
+    }
+
+    /// Returns the time of the last access, in seconds since the Unix epoch.
+    pub fn atime(&self) -> u64 {
+        self.atime as u64
+    }
+
+    /// Sets the time of the last access, saturating at the latest storable time.
+    pub fn set_atime(&mut self, time: u64) {
+        self.atime = u32::try_from(time).unwrap_or(u32::MAX);
+    }
+
+    /// Checks whether the node stores its contents inline instead of in blocks.
+    pub fn is_inline(&self) -> bool {
+        self.flags & FLAG_INLINE != 0
+    }
+
+    /// Sets whether the node stores its contents inline.
+    /// The space shared by the extents and the inline contents is cleared either way.
+    pub fn set_inline(&mut self, inline: bool) {
+        if inline {
+            self.flags |= FLAG_INLINE;
+        } else {
+            self.flags &= !FLAG_INLINE;
+        }
+        self.extents = Default::default();
+    }
+
+    /// Returns the inline contents of the node, empty if it isn't inline.
+    pub fn inline_data(&self) -> &[u8] {
+        if !self.is_inline() {
+            return &[];
+        }
+        &self.extents.as_bytes()[..self.size.min(INLINE_MAX)]
+    }
+
+    /// Returns a mutable reference to the whole inline area of the node, empty if it isn't inline.
+    pub fn inline_data_mut(&mut self) -> &mut [u8] {
+        if !self.is_inline() {
+            return &mut [];
+        }
+        self.extents.as_mut_bytes()
+    }
+
+    /// Returns a reference to node's extents, empty if the node is inline.
     pub fn get_extents(&self) -> &[Extent] {
+        if self.is_inline() {
+            return &[];
+        }
         &self.extents
     }
 
-    /// Returns a mutable reference to node's extents.
+    /// Returns a mutable reference to node's extents, empty if the node is inline.
     pub fn get_mut_extents(&mut self) -> &mut [Extent] {
+        if self.is_inline() {
+            return &mut [];
+        }
         &mut self.extents
     }
 
     /// Resolves `block offset` within the file into a block id.
     pub fn get_block_id(&self, mut block_offset: usize) -> Option<usize> {
-        for extent in self.extents.iter().take_while(|e| !e.is_null()) {
+        for extent in self.get_extents().iter().take_while(|e| !e.is_null()) {
             let extent_len = extent.len();
             if extent_len > block_offset {
                 return if extent.is_hole() {
@@ -106,7 +177,7 @@ impl Node {
 
     /// Returns the number of blocks that belong to the node.
     pub fn block_count(&self) -> usize {
-        self.extents
+        self.get_extents()
             .iter()
             .filter(|e| !e.is_null() && !e.is_hole())
             .map(|e| e.len())
@@ -116,6 +187,9 @@ impl Node {
     /// Maps the block at `block offset` within the file to `block id`.
     pub fn map_block(&mut self, mut block_offset: usize, block_id: usize) -> Result<()> {
         assert!(block_id != 0);
+        if self.is_inline() {
+            return Err(Error::Inline);
+        }
         for curr in 0..self.extents.len() {
             if self.extents[curr].is_null() {
                 // All allocated extents were passed or there was none
@@ -154,25 +228,29 @@ impl Node {
                     return Err(Error::AlreadyMapped);
                 }
 
-                // Split the hole into three extents:
-                let mut exts = [Extent::default(); 3];
-                exts[0].end = block_offset; // Left hole
-                exts[1].start = block_id;
-                exts[1].end = block_id + 1;
-                exts[2].end = blocks_in_curr - block_offset - 1; // Right hole
-                // Remove empty hole, if there is one
-                // (i.e. the first/last block of the hole is mapped)
-                let exts: Vec<Extent> = exts.into_iter().filter(|e| !e.is_null()).collect();
-                let extra = exts.len() - 1; // How many new extents need to be inserted
-                let last = self.extents.iter().rposition(|e| !e.is_null()).unwrap();
-                if last + extra > (self.extents.len() - 1) {
-                    // No room for extent insertion
+                // Split the hole into the left hole, the mapped block and the right hole.
+                // The block joins the neighbouring extents if it's physically contiguous with them.
+                let pieces = [
+                    Extent::new(0, block_offset),
+                    Extent::new(block_id, block_id + 1),
+                    Extent::new(0, blocks_in_curr - block_offset - 1),
+                ];
+                let mut extents = Vec::new();
+                let rest = self.extents[(curr + 1)..]
+                    .iter()
+                    .take_while(|e| !e.is_null());
+                let all = self.extents[..curr]
+                    .iter()
+                    .chain(pieces.iter().filter(|e| !e.is_null()))
+                    .chain(rest);
+                for extent in all {
+                    push_merged(&mut extents, *extent);
+                }
+                if extents.len() > self.extents.len() {
                     return Err(Error::OutOfExtents);
                 }
-                let next = curr + 1;
-                self.extents.copy_within(next..=last, next + extra);
-                self.extents[curr..=(curr + extra)].copy_from_slice(&exts);
-
+                self.extents = Default::default();
+                self.extents[..extents.len()].copy_from_slice(&extents);
                 return Ok(());
             }
             block_offset -= blocks_in_curr;
@@ -180,9 +258,72 @@ impl Node {
         Err(Error::OutOfExtents)
     }
 
+    /// Unmaps blocks in the `[block_start, block_end)` range of block offsets, turning them into a hole.
+    /// Extents are split as needed. Returns the physical spans that were unmapped.
+    pub fn unmap_blocks(
+        &mut self,
+        block_start: usize,
+        block_end: usize,
+    ) -> Result<Vec<(usize, usize)>> {
+        if self.is_inline() {
+            return Err(Error::Inline);
+        }
+        let mut extents = Vec::new();
+        let mut unmapped = Vec::new();
+        let mut pos = 0;
+        for extent in self.extents.iter().take_while(|e| !e.is_null()) {
+            let (ext_start, ext_end) = (pos, pos + extent.len());
+            pos = ext_end;
+            let lo = block_start.max(ext_start);
+            let hi = block_end.min(ext_end);
+            if extent.is_hole() || lo >= hi {
+                push_merged(&mut extents, *extent);
+                continue;
+            }
+            // Split into the left part, the hole and the right part
+            let (lo, hi) = (extent.start + lo - ext_start, extent.start + hi - ext_start);
+            if lo > extent.start {
+                push_merged(
+                    &mut extents,
+                    Extent {
+                        start: extent.start,
+                        end: lo,
+                    },
+                );
+            }
+            push_merged(
+                &mut extents,
+                Extent {
+                    start: 0,
+                    end: hi - lo,
+                },
+            );
+            if hi < extent.end {
+                push_merged(
+                    &mut extents,
+                    Extent {
+                        start: hi,
+                        end: extent.end,
+                    },
+                );
+            }
+            unmapped.push((lo, hi));
+        }
+
+        if extents.len() > self.extents.len() {
+            return Err(Error::OutOfExtents);
+        }
+        self.extents = Default::default();
+        self.extents[..extents.len()].copy_from_slice(&extents);
+        Ok(unmapped)
+    }
+
     /// Appends a sparse region of 'count' blocks to the end of node's extents.
     pub fn append_hole(&mut self, count: usize) -> Result<()> {
         assert!(count != 0);
+        if self.is_inline() {
+            return Err(Error::Inline);
+        }
         for i in 0..self.extents.len() {
             if self.extents[i].is_null() {
                 // Check if can be merged with the previous extent
@@ -202,6 +343,22 @@ impl Node {
     }
 }
 
+/// Appends the extent, merging it into the last one if both are holes
+/// or the last one's blocks are physically followed by its own.
+fn push_merged(extents: &mut Vec<Extent>, extent: Extent) {
+    if let Some(last) = extents.last_mut() {
+        if last.is_hole() && extent.is_hole() {
+            last.end += extent.len();
+            return;
+        }
+        if !last.is_hole() && !extent.is_hole() && last.end == extent.start {
+            last.end = extent.end;
+            return;
+        }
+    }
+    extents.push(extent);
+}
+
 /// Represents file types.
 #[repr(u8)]
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -223,6 +380,12 @@ pub struct Extent {
 }
 
 impl Extent {
+    /// Constructs an [Extent] spanning blocks `[start, end)`.
+    /// A zero `start` describes a hole of `end` blocks.
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
     /// Returns the block that marks the start of the extent.
     pub fn start(&self) -> usize {
         self.start
@@ -251,7 +414,7 @@ impl Extent {
 
     /// Shrinks the extent to `len`.
     pub fn shrink(&mut self, len: usize) {
-        self.end = len;
+        self.end = self.start + len;
     }
 
     /// Returns the number of blocks in this extent.
@@ -259,6 +422,11 @@ impl Extent {
         self.end - self.start
     }
 
+    /// Checks whether the extent spans no blocks.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Represesnts itself as a (start, end) span.
     pub fn span(&self) -> (usize, usize) {
         (self.start, self.end)
@@ -271,4 +439,6 @@ type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     OutOfExtents,
     AlreadyMapped,
+    /// The node stores its contents inline, so it has no extents to change.
+    Inline,
 }
@@ -38,6 +38,9 @@ impl<'a> Path<'a> {
 
     /// Splits the path into its parent path and the file name.
     /// Returns `None` if the path is empty or is the root directory `/`.
+    ///
+    /// The parent keeps the leading `/` of an absolute path, so `/name` splits into `/`,
+    /// while a bare `name` splits into the empty path, which resolves to the start directory.
     pub fn split_last(&'a self) -> Option<(Path<'a>, Part<'a>)> {
         let name = self.as_parts().next_back()?;
         if name == "/" {
@@ -48,6 +51,11 @@ impl<'a> Path<'a> {
         Some((Path::new(parent), name))
     }
 
+    /// Returns the path as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     /// Returns the path as a byte slice.
     pub fn as_bytes(&self) -> &[u8] {
         self.0.as_bytes()
@@ -66,6 +74,42 @@ impl<'a> Path<'a> {
     }
 }
 
+/// A component of a normalized path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Component<'a> {
+    /// The root directory `/`.
+    Root,
+    /// A `..` part, resolved through the directory's `..` entry.
+    Parent,
+    /// A named entry.
+    Normal(Part<'a>),
+}
+
+/// Normalizes the path into its components.
+/// Repeated and trailing slashes are ignored and `.` parts are dropped.
+/// If `cwd` is given, a relative path is resolved against it.
+///
+/// `..` parts are kept as is, since folding them lexically would skip symlinks
+/// and missing or non-directory parts that precede them.
+pub fn normalize<'a>(path: &'a Path<'a>, cwd: Option<&'a Path<'a>>) -> Vec<Component<'a>> {
+    let mut components = match cwd {
+        Some(cwd) if !path.is_absolute() => normalize(cwd, None),
+        _ => Vec::new(),
+    };
+    for part in path.as_parts() {
+        match part.as_ref() {
+            "/" => {
+                components.clear();
+                components.push(Component::Root);
+            }
+            "." => (),
+            ".." => components.push(Component::Parent),
+            _ => components.push(Component::Normal(part)),
+        }
+    }
+    components
+}
+
 #[derive(Debug)]
 pub enum Error {
     CorruptedPath,
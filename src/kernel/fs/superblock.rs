@@ -1,5 +1,6 @@
 use super::{alloc_map::AllocFlag, node::Node};
 use crate::hardware::storage::block::{BLOCK_SIZE, Block};
+use std::hash::{BuildHasher, Hasher, RandomState};
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
 /// A magic number to identify the filesystem.
@@ -8,9 +9,12 @@ pub const MAGIC: usize = 0xF5F5_F5F5;
 /// Superblock id.
 pub const SUPER_ID: usize = 0;
 
+/// How long a filesystem label can be.
+pub const LABEL_MAX: usize = 32;
+
 /// Represents metadata about the file system.
 #[repr(C)]
-#[derive(FromBytes, IntoBytes, Immutable)]
+#[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
 pub struct Superblock {
     pub magic: usize,
     pub block_count: usize,
@@ -19,27 +23,55 @@ pub struct Superblock {
     pub node_map_start: usize,
     pub node_table_start: usize,
     pub data_start: usize,
+    /// A human-readable name of the filesystem, padded with null bytes.
+    pub label: [u8; LABEL_MAX],
+    /// A unique id of the filesystem, generated when it's formatted.
+    pub uuid: [u8; 16],
+    /// A checksum over the rest of the superblock.
+    pub checksum: u32,
+    /// Nonzero if a backup copy of the superblock is kept in the last block.
+    /// Older filesystems have zero in its place.
+    pub backup: u32,
 }
 
 impl Superblock {
     /// Constructs a superblock with given block and node count.
+    ///
+    /// Every region starts on a block boundary: a map that only partially fills its last block
+    /// still owns that whole block, so the next region never overlaps it.
+    ///
+    /// # Panics
+    /// Panics if:
+    /// - `block_count` or `node_count` is zero, which leaves a region empty
+    /// - the regions are too large to be addressed
     pub fn new(block_count: usize, node_count: usize) -> Self {
-        let block_map_bytes = block_count * (size_of::<AllocFlag>());
+        Self::try_new(block_count, node_count)
+            .expect("Regions must not be empty and must be addressable")
+    }
+
+    /// Constructs a superblock like [Superblock::new].
+    /// Returns `None` instead of panicking if a region would be empty,
+    /// or if the sizes of the regions overflow.
+    pub fn try_new(block_count: usize, node_count: usize) -> Option<Self> {
+        if block_count == 0 || node_count == 0 {
+            return None;
+        }
+        let block_map_bytes = block_count.checked_mul(size_of::<AllocFlag>())?;
         let block_map_blocks = block_map_bytes.div_ceil(BLOCK_SIZE);
 
-        let node_map_bytes = node_count * (size_of::<AllocFlag>());
+        let node_map_bytes = node_count.checked_mul(size_of::<AllocFlag>())?;
         let node_map_blocks = node_map_bytes.div_ceil(BLOCK_SIZE);
 
-        let node_table_bytes = node_count * (size_of::<Node>());
+        let node_table_bytes = node_count.checked_mul(size_of::<Node>())?;
         let node_table_blocks = node_table_bytes.div_ceil(BLOCK_SIZE);
 
         // Superblock lives in the 0th block
         let block_map_start = 1;
         let node_map_start = block_map_start + block_map_blocks;
-        let node_table_start = node_map_start + node_map_blocks;
-        let data_start = node_table_start + node_table_blocks;
+        let node_table_start = node_map_start.checked_add(node_map_blocks)?;
+        let data_start = node_table_start.checked_add(node_table_blocks)?;
 
-        Self {
+        let mut superblock = Self {
             magic: MAGIC,
             block_count,
             node_count,
@@ -47,8 +79,75 @@ impl Superblock {
             node_map_start,
             node_table_start,
             data_start,
-        }
+            label: [0u8; LABEL_MAX],
+            uuid: [0u8; 16],
+            checksum: 0,
+            backup: 1,
+        };
+        superblock.update_checksum();
+        Some(superblock)
+    }
+
+    /// Returns the label of the filesystem.
+    pub fn label(&self) -> &str {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(LABEL_MAX);
+        str::from_utf8(&self.label[..len]).unwrap_or_default()
+    }
+
+    /// Formats the UUID in the canonical hyphenated form.
+    pub fn uuid_string(&self) -> String {
+        let hex: Vec<String> = self.uuid.iter().map(|b| format!("{:02x}", b)).collect();
+        [
+            &hex[0..4],
+            &hex[4..6],
+            &hex[6..8],
+            &hex[8..10],
+            &hex[10..16],
+        ]
+        .map(|group| group.concat())
+        .join("-")
+    }
+
+    /// Computes the FNV-1a hash of the superblock, excluding the checksum itself.
+    fn compute_checksum(&self) -> u32 {
+        const OFFSET_BASIS: u32 = 0x811C_9DC5;
+        const PRIME: u32 = 0x0100_0193;
+        let copy = Self {
+            checksum: 0,
+            ..*self
+        };
+        copy.as_bytes().iter().fold(OFFSET_BASIS, |hash, &byte| {
+            (hash ^ byte as u32).wrapping_mul(PRIME)
+        })
+    }
+
+    /// Returns the id of the block holding the backup copy of the superblock, if one is kept.
+    pub fn backup_id(&self) -> Option<usize> {
+        (self.backup != 0).then(|| self.block_count - 1)
+    }
+
+    /// Recomputes the checksum. Must be called after any field is changed.
+    pub fn update_checksum(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    /// Checks whether the checksum matches the rest of the superblock.
+    pub fn is_checksum_valid(&self) -> bool {
+        self.checksum == self.compute_checksum()
+    }
+}
+
+/// Generates a random (version 4) UUID.
+pub fn random_uuid() -> [u8; 16] {
+    // Every 'RandomState' is seeded with fresh random keys
+    let mut uuid = [0u8; 16];
+    for half in uuid.chunks_mut(8) {
+        let hash = RandomState::new().build_hasher().finish();
+        half.copy_from_slice(&hash.to_le_bytes());
     }
+    uuid[6] = (uuid[6] & 0x0F) | 0x40;
+    uuid[8] = (uuid[8] & 0x3F) | 0x80;
+    uuid
 }
 
 impl From<&Superblock> for Block {
@@ -1,55 +1,262 @@
-use std::collections::BTreeMap;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, btree_map::Entry},
+    ops::Range,
+};
 
 use zerocopy::{FromBytes, IntoBytes, TryFromBytes};
 
 use crate::{
     hardware::storage::{
-        Storage,
+        self, BlockDevice,
         block::{BLOCK_SIZE, Block},
     },
-    kernel::fs::{
-        Filesystem,
-        alloc_map::{self, AllocMap},
-        directory::{self, Dir, DirEntry, DirEntryName},
-        node::{self, FileType, NODE_SIZE, NODES_PER_BLOCK, Node, NodePtr},
-        path::{self, Path},
+    kernel::{
+        fs::{
+            Filesystem,
+            alloc_map::{self, AllocFlag, AllocMap},
+            directory::{self, Dir, DirEntry, DirEntryName},
+            node::{self, FileType, INLINE_MAX, NODE_SIZE, NODES_PER_BLOCK, Node, NodePtr},
+            path::{self, Component, Part, Path},
+            superblock::{self, Superblock},
+            xattr::{self, Xattrs},
+        },
+        hash::Sha256,
     },
 };
 
 /// A cache to buffer changes.
 type Changes = BTreeMap<usize, Block>;
 
+/// Tracks directories entered during a traversal.
+type Visited = BTreeSet<usize>;
+
+/// Marks the directory as entered, failing if it already was, which means the tree has a cycle.
+fn visit(visited: &mut Visited, dir_ptr: NodePtr) -> Result<()> {
+    if !visited.insert(dir_ptr.id()) {
+        return Err(Error::DirectoryCycle);
+    }
+    Ok(())
+}
+
 /// A filesystem operation that buffers changes in memory before commiting them to persistent storage.
 pub struct Transaction<'a> {
     fs: &'a mut Filesystem,
-    storage: &'a mut Storage,
+    storage: &'a mut dyn BlockDevice,
     changes: Changes,
+    /// Ids of blocks already written to storage before the commit.
+    flushed: BTreeSet<usize>,
+    /// How many buffered blocks trigger the next flush.
+    flush_at: usize,
+    sparse: bool,
+    /// Time stamped on modified nodes, in seconds since the Unix epoch.
+    now: u64,
+    /// In-memory state from before the transaction, restored unless it commits.
+    snapshot: Option<Snapshot>,
+}
+
+/// In-memory filesystem state that a transaction may change.
+struct Snapshot {
+    superblock: Superblock,
+    block_map: AllocMap,
+    node_map: AllocMap,
 }
 
 impl<'a> Transaction<'a> {
     /// Constructs a [Transaction] for the given filesystem and storage.
-    pub fn new(fs: &'a mut Filesystem, storage: &'a mut Storage) -> Self {
+    pub fn new(fs: &'a mut Filesystem, storage: &'a mut dyn BlockDevice) -> Self {
+        let flush_at = fs.flush_limit;
+        let snapshot = Snapshot {
+            superblock: fs.superblock,
+            block_map: fs.block_map.clone(),
+            node_map: fs.node_map.clone(),
+        };
         Self {
             fs,
             storage,
             changes: Changes::new(),
+            flushed: BTreeSet::new(),
+            flush_at,
+            sparse: false,
+            now: 0,
+            snapshot: Some(snapshot),
         }
     }
 
+    /// Sets the time stamped on the nodes the transaction modifies, in seconds since the Unix epoch.
+    pub fn set_time(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    /// Returns the time stamped on the nodes the transaction modifies.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Sets whether file writes leave fully-zero blocks that aren't allocated yet as holes.
+    pub fn set_sparse(&mut self, sparse: bool) {
+        self.sparse = sparse;
+    }
+
     /// Commits the transaction to persistent storage, consuming the transaction.
-    pub fn commit(mut self) {
+    ///
+    /// Data blocks are written before metadata, so that an interrupted commit
+    /// can't expose a node pointing at unwritten data.
+    ///
+    /// # Errors
+    /// Returns `Err` listing every block that failed to be written.
+    /// Once a region fails, later regions are not written.
+    pub fn commit(mut self) -> std::result::Result<(), CommitError> {
+        if self.fs.crashed {
+            return Err(CommitError {
+                failures: Vec::new(),
+                crashed: true,
+            });
+        }
         self.sync_maps();
-        for (&block_id, block) in self.changes.iter() {
-            self.storage
-                .write_block(block_id, block)
-                .expect("'block_id' must be a valid block id")
+        let mut block_ids: Vec<usize> = self.changes.keys().copied().collect();
+        block_ids.sort_by_key(|&id| self.write_order(id));
+        // A simulated power loss cuts the commit short
+        let crash_point = self.fs.crash_point.take();
+        if let Some(crash_point) = crash_point {
+            let last_order = match crash_point {
+                CrashPoint::AfterDataBeforeMeta => 0,
+                CrashPoint::AfterMapsBeforeNodes => 1,
+                CrashPoint::BeforeSuperblock => 2,
+                CrashPoint::AfterHalfBlocks => 3,
+            };
+            let mut count = block_ids
+                .iter()
+                .take_while(|&&id| self.write_order(id) <= last_order)
+                .count();
+            if crash_point == CrashPoint::AfterHalfBlocks {
+                count /= 2;
+            }
+            block_ids.truncate(count);
+        }
+        // The record is only built when someone observes it
+        let record = (self.fs.commit_hook.is_some()
+            && !(block_ids.is_empty() && self.flushed.is_empty()))
+        .then(|| self.commit_record());
+
+        let mut failures = Vec::new();
+        let mut curr_order = 0;
+        for block_id in block_ids {
+            let order = self.write_order(block_id);
+            if order != curr_order {
+                if !failures.is_empty() {
+                    break;
+                }
+                curr_order = order;
+            }
+            if let Err(e) = self.storage.write_block(block_id, &self.changes[&block_id]) {
+                failures.push((block_id, e));
+            }
+        }
+
+        if crash_point.is_some() {
+            self.fs.crashed = true;
+        }
+        if !failures.is_empty() || self.fs.crashed {
+            return Err(CommitError {
+                failures,
+                crashed: self.fs.crashed,
+            });
+        }
+        self.snapshot = None;
+        if let (Some(hook), Some(record)) = (self.fs.commit_hook.as_mut(), record) {
+            hook(&record);
+        }
+        Ok(())
+    }
+
+    /// Describes the blocks and nodes that the transaction changes.
+    /// Nodes are found by comparing changed node table blocks against the stored ones.
+    fn commit_record(&self) -> CommitRecord {
+        let superblock = &self.fs.superblock;
+        let node_table = superblock.node_table_start..superblock.data_start;
+        let mut nodes = Vec::new();
+        for (&block_id, block) in self.changes.range(node_table) {
+            let stored = self.storage.read_block(block_id).unwrap_or_default();
+            let first_id = (block_id - superblock.node_table_start) * NODES_PER_BLOCK;
+            for i in 0..NODES_PER_BLOCK {
+                let slot = (i * NODE_SIZE)..((i + 1) * NODE_SIZE);
+                if block.data[slot.clone()] != stored.data[slot] {
+                    nodes.push(first_id + i);
+                }
+            }
+        }
+        let mut blocks: Vec<usize> = self.changes.keys().copied().collect();
+        blocks.extend(&self.flushed);
+        blocks.sort();
+        CommitRecord { blocks, nodes }
+    }
+
+    /// Discards the transaction, consuming it.
+    /// In-memory filesystem state is restored to what it was before the transaction.
+    /// Returns a summary of what the transaction would have changed.
+    ///
+    /// Dropping a transaction without committing it discards it the same way.
+    pub fn abort(self) -> Summary {
+        self.summary()
+    }
+
+    /// Summarizes the changes buffered by the transaction so far.
+    /// Maps that can't be read from storage are compared against the state
+    /// from before the transaction instead.
+    pub fn summary(&self) -> Summary {
+        let superblock = &self.fs.superblock;
+        let snapshot = self.snapshot.as_ref();
+        let block_map = Filesystem::read_map(
+            self.storage,
+            superblock.block_map_start,
+            superblock.node_map_start,
+            superblock.block_count,
+        )
+        .ok();
+        let block_map = block_map.as_ref().or(snapshot.map(|s| &s.block_map));
+        let node_map = Filesystem::read_map(
+            self.storage,
+            superblock.node_map_start,
+            superblock.node_table_start,
+            superblock.node_count,
+        )
+        .ok();
+        let node_map = node_map.as_ref().or(snapshot.map(|s| &s.node_map));
+        let (blocks_allocated, blocks_freed) = block_map
+            .map(|map| self.fs.block_map.diff(map))
+            .unwrap_or_default();
+        let (nodes_allocated, nodes_freed) = node_map
+            .map(|map| self.fs.node_map.diff(map))
+            .unwrap_or_default();
+        Summary {
+            blocks_written: self.changes.len() + self.flushed.len(),
+            blocks_allocated,
+            blocks_freed,
+            nodes_allocated,
+            nodes_freed,
+        }
+    }
+
+    /// Returns the rank of the block's region in the commit order:
+    /// data, allocation maps, node table, superblock.
+    fn write_order(&self, block_id: usize) -> usize {
+        let superblock = &self.fs.superblock;
+        if block_id >= superblock.data_start {
+            0
+        } else if block_id >= superblock.node_table_start {
+            2
+        } else if block_id >= superblock.block_map_start {
+            1
+        } else {
+            3
         }
     }
 
     /// Queues a synchronization of allocation maps.
     fn sync_maps(&mut self) {
         let fs = &self.fs;
-        let storage = &self.storage;
+        let storage = &*self.storage;
         let changes = &mut self.changes;
         Self::_sync_map(
             storage,
@@ -62,20 +269,84 @@ impl<'a> Transaction<'a> {
 
     // Internal implementation of 'sync_maps' for a single map.
     // Separated to split borrows.
-    fn _sync_map(storage: &Storage, changes: &mut Changes, map: &AllocMap, map_start: usize) {
+    fn _sync_map(
+        storage: &dyn BlockDevice,
+        changes: &mut Changes,
+        map: &AllocMap,
+        map_start: usize,
+    ) {
         let bytes = map.as_slice().as_bytes();
         for (i, chunk) in bytes.chunks(BLOCK_SIZE).enumerate() {
             let block_mem = Block::read_from_bytes(chunk).unwrap_or_else(|_| Block::new(chunk));
             // Check if in-memory and stored blocks differ
+            // A block that can't be read is rewritten, so a failure surfaces in the commit
             let block_id = map_start + i;
-            let block_stored = Self::_read_block(storage, changes, block_id)
-                .expect("Must be able to read the allocation map");
-            if block_mem.data != block_stored.data {
+            let is_stale = Self::_read_block(storage, changes, block_id)
+                .map_or(true, |block| block.data != block_mem.data);
+            if is_stale {
                 Self::_write_block(changes, map_start + i, &block_mem);
             }
         }
     }
 
+    /// Extends the filesystem to span `block_count` blocks of the storage.
+    /// The block allocation map must still fit into its region.
+    pub fn grow(&mut self, block_count: usize) -> Result<()> {
+        let superblock = &self.fs.superblock;
+        if block_count < superblock.block_count {
+            return Err(Error::NotPermitted);
+        }
+        if block_count > self.storage.block_count() {
+            return Err(Error::BlockIdOutOfBounds);
+        }
+        let map_blocks = superblock.node_map_start - superblock.block_map_start;
+        if block_count * size_of::<AllocFlag>() > map_blocks * BLOCK_SIZE {
+            return Err(Error::MapRegionTooSmall);
+        }
+
+        // The backup superblock moves to the new last block
+        let old_backup_id = superblock.backup_id();
+        self.fs.block_map.grow(block_count);
+        self.fs.superblock.block_count = block_count;
+        if let (Some(old_id), Some(new_id)) = (old_backup_id, self.fs.superblock.backup_id())
+            && old_id != new_id
+        {
+            self.fs
+                .block_map
+                .free((old_id, old_id + 1))
+                .map_err(Error::Alloc)?;
+            self.fs
+                .block_map
+                .allocate_at(new_id)
+                .map_err(Error::Alloc)?;
+        }
+        self.fs.superblock.update_checksum();
+        self.write_superblock();
+        Ok(())
+    }
+
+    /// Sets the label of the filesystem.
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        if label.len() > superblock::LABEL_MAX || label.contains('\0') {
+            return Err(Error::InvalidLabel);
+        }
+        let superblock = &mut self.fs.superblock;
+        superblock.label = [0u8; superblock::LABEL_MAX];
+        superblock.label[..label.len()].copy_from_slice(label.as_bytes());
+        superblock.update_checksum();
+        self.write_superblock();
+        Ok(())
+    }
+
+    /// Queues a write of the superblock, along with its backup copy if one is kept.
+    pub fn write_superblock(&mut self) {
+        let superblock = Block::from(&self.fs.superblock);
+        self.write_block(superblock::SUPER_ID, &superblock);
+        if let Some(backup_id) = self.fs.superblock.backup_id() {
+            self.write_block(backup_id, &superblock);
+        }
+    }
+
     /// Reads the node from the node table.
     pub fn read_node(&self, node_ptr: NodePtr) -> Result<Node> {
         let block_id = self
@@ -91,7 +362,33 @@ impl<'a> Transaction<'a> {
         )
     }
 
+    /// Reads the nodes with ids in `indices`, returning them in the same order.
+    /// Each node table block is read once, however many of the nodes share it.
+    pub fn read_nodes(&self, indices: &[usize]) -> Result<Vec<Node>> {
+        let mut blocks = BTreeMap::new();
+        let mut nodes = Vec::with_capacity(indices.len());
+        for &id in indices {
+            let node_ptr = NodePtr::new(id);
+            let block_id = self
+                .get_node_block_id(node_ptr)
+                .ok_or(Error::NodePtrOutOfBounds)?;
+            let block = match blocks.entry(block_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.read_block(block_id)?),
+            };
+            let offset = self
+                .get_node_offset(node_ptr)
+                .ok_or(Error::NodePtrOutOfBounds)?;
+            nodes.push(
+                Node::try_read_from_bytes(&block.data[offset..(offset + NODE_SIZE)])
+                    .expect("'bytes' must be a valid 'Node'"),
+            );
+        }
+        Ok(nodes)
+    }
+
     // Queues a write of the node to the node table.
+    // Nothing is queued if the node is unchanged.
     pub fn write_node(&mut self, node_ptr: NodePtr, node: Node) -> Result<()> {
         let block_id = self
             .get_node_block_id(node_ptr)
@@ -100,23 +397,106 @@ impl<'a> Transaction<'a> {
         let offset = self
             .get_node_offset(node_ptr)
             .ok_or(Error::NodePtrOutOfBounds)?;
+        if block.data[offset..(offset + NODE_SIZE)] == *node.as_bytes() {
+            return Ok(());
+        }
         block.data[offset..(offset + NODE_SIZE)].copy_from_slice(node.as_bytes());
         self.write_block(block_id, &block);
         Ok(())
     }
 
+    /// Replaces the extents of the node at `node_ptr` with `extents`.
+    /// Blocks referenced by the new extents are marked as allocated.
+    /// Blocks referenced only by the old extents are left allocated.
+    ///
+    /// # Errors
+    /// Returns `Err` if:
+    /// - the node is not allocated
+    /// - there are more extents than a node can hold
+    /// - an extent is empty or references blocks outside of the data region
+    #[cfg(feature = "debug-extents")]
+    pub fn set_extents(&mut self, node_ptr: NodePtr, extents: &[node::Extent]) -> Result<()> {
+        if !self.fs.node_map.is_used(node_ptr.id()) {
+            return Err(Error::NodeNotFound);
+        }
+        let mut node = self.read_node(node_ptr)?;
+        node.set_inline(false);
+        if extents.len() > node.get_extents().len() {
+            return Err(Error::Node(node::Error::OutOfExtents));
+        }
+        let superblock = &self.fs.superblock;
+        for extent in extents {
+            let out_of_bounds = !extent.is_hole()
+                && (extent.start() < superblock.data_start
+                    || extent.end() > superblock.block_count);
+            if extent.is_null() || extent.start() > extent.end() || out_of_bounds {
+                return Err(Error::InvalidExtent);
+            }
+        }
+
+        for extent in extents.iter().filter(|e| !e.is_hole()) {
+            for id in extent.start()..extent.end() {
+                if !self.fs.block_map.is_used(id) {
+                    self.fs.block_map.allocate_at(id).map_err(Error::Alloc)?;
+                }
+            }
+        }
+        let node_extents = node.get_mut_extents();
+        node_extents.fill(node::Extent::default());
+        node_extents[..extents.len()].copy_from_slice(extents);
+        self.write_node(node_ptr, node)
+    }
+
     /// Allocates a [Node], returning it and its pointer.
+    /// Files and symlinks start out storing their contents inline.
+    /// The node is allocated at the lowest free id, so a freed id is the next one reused here.
+    /// Nodes of new directory entries come from [Transaction::create_node_near] instead,
+    /// which doesn't reuse the lowest id first.
     pub fn create_node(&mut self, filetype: FileType) -> Result<(Node, NodePtr)> {
-        let node = Node::new(filetype);
+        let mut node = Node::new(filetype);
+        node.set_inline(filetype != FileType::Dir);
+        node.set_atime(self.now);
+        node.set_mtime(self.now);
         let (id, _) = self.fs.node_map.allocate(1).map_err(Error::Alloc)?;
         let node_ptr = NodePtr::new(id);
         self.write_node(node_ptr, node)?;
         Ok((node, node_ptr))
     }
 
+    /// Creates a node like [Transaction::create_node],
+    /// allocating it as close to the node at `hint_ptr` as possible.
+    pub fn create_node_near(
+        &mut self,
+        filetype: FileType,
+        hint_ptr: NodePtr,
+    ) -> Result<(Node, NodePtr)> {
+        let mut node = Node::new(filetype);
+        node.set_inline(filetype != FileType::Dir);
+        node.set_atime(self.now);
+        node.set_mtime(self.now);
+        let id = self
+            .fs
+            .node_map
+            .allocate_near(hint_ptr.id())
+            .map_err(Error::Alloc)?;
+        let node_ptr = NodePtr::new(id);
+        self.write_node(node_ptr, node)?;
+        Ok((node, node_ptr))
+    }
+
+    /// Reads every allocated node, skipping the null node.
+    pub fn list_nodes(&self) -> Result<Vec<(NodePtr, Node)>> {
+        let ids: Vec<usize> = self.fs.node_map.used_ids().filter(|&id| id != 0).collect();
+        let nodes = self.read_nodes(&ids)?;
+        Ok(ids.into_iter().map(NodePtr::new).zip(nodes).collect())
+    }
+
     /// Reads a number of bytes from the file starting from a given offset into the buffer.
-    /// Returns the number of bytes read.
+    /// Returns the number of bytes read, which is zero for an empty buffer.
     pub fn read_file_at(&self, node_ptr: NodePtr, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
         let node = self.read_node(node_ptr)?;
 
         if offset >= node.size {
@@ -125,6 +505,10 @@ impl<'a> Transaction<'a> {
 
         let bytes_available = node.size - offset;
         let bytes_to_read = bytes_available.min(buf.len());
+        if node.is_inline() {
+            buf[..bytes_to_read].copy_from_slice(&node.inline_data()[offset..][..bytes_to_read]);
+            return Ok(bytes_to_read);
+        }
         let mut bytes_read = 0;
 
         while bytes_read != bytes_to_read {
@@ -148,21 +532,111 @@ impl<'a> Transaction<'a> {
         Ok(bytes_read)
     }
 
+    /// Returns up to `len` bytes of the file at `offset` without copying them into a buffer,
+    /// if the block holding them can be borrowed. Bytes past the end of the file are cut off,
+    /// and a hole reads as zeroes.
+    ///
+    /// Only ranges within a single block can be peeked:
+    /// `offset` must be block-aligned and `len` at most [BLOCK_SIZE],
+    /// otherwise [Error::Unaligned] is returned.
+    pub fn peek(&self, node_ptr: NodePtr, offset: usize, len: usize) -> Result<Cow<'_, [u8]>> {
+        if !offset.is_multiple_of(BLOCK_SIZE) || len > BLOCK_SIZE {
+            return Err(Error::Unaligned);
+        }
+        let node = self.read_node(node_ptr)?;
+        let len = len.min(node.size.saturating_sub(offset));
+        if node.is_inline() {
+            let data = node
+                .inline_data()
+                .get(offset..offset + len)
+                .unwrap_or_default();
+            return Ok(Cow::Owned(data.to_vec()));
+        }
+        match node.get_block_id_from_offset(offset) {
+            Some(block_id) => Ok(match self.read_block_ref(block_id)? {
+                Cow::Borrowed(block) => Cow::Borrowed(&block.data[..len]),
+                Cow::Owned(block) => Cow::Owned(block.data[..len].to_vec()),
+            }),
+            None => Ok(Cow::Owned(vec![0u8; len])),
+        }
+    }
+
+    /// Computes the SHA-256 digest of the file's contents, reading it a block at a time.
+    /// Holes are hashed as zero bytes.
+    pub fn hash_file(&self, node_ptr: NodePtr) -> Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        let mut buf = [0u8; BLOCK_SIZE];
+        let mut offset = 0;
+        loop {
+            let bytes_read = self.read_file_at(node_ptr, offset, &mut buf)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buf[..bytes_read]);
+            offset += bytes_read;
+        }
+        Ok(hasher.finalize())
+    }
+
     // BUG: Doesn't allow to write past the end of the file yet.
     /// Writes a byte slice to the file starting from a given offset.
-    /// Returns the number of byttes written.
+    /// Returns the number of byttes written, which is zero for empty `data`.
+    ///
+    /// If the filesystem runs out of blocks or the node out of extents midway,
+    /// the write is short: the bytes written so far are kept and counted.
     pub fn write_file_at(
         &mut self,
         node_ptr: NodePtr,
         offset: usize,
         data: &[u8],
     ) -> Result<usize> {
+        self.write_file_at_reserved(node_ptr, offset, data, None)
+    }
+
+    /// Writes a byte slice to the file like [Transaction::write_file_at],
+    /// serving new blocks from `reservation` when there is one.
+    pub fn write_file_at_reserved(
+        &mut self,
+        node_ptr: NodePtr,
+        offset: usize,
+        data: &[u8],
+        reservation: Option<&mut Reservation>,
+    ) -> Result<usize> {
+        self._write_file_at(node_ptr, offset, data, reservation, true)
+    }
+
+    // Internal implementation of 'write_file_at_reserved'.
+    // If `short` is false, running out of space fails the write instead of ending it short.
+    fn _write_file_at(
+        &mut self,
+        node_ptr: NodePtr,
+        offset: usize,
+        data: &[u8],
+        mut reservation: Option<&mut Reservation>,
+        short: bool,
+    ) -> Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
         let mut node = self.read_node(node_ptr)?;
 
         if offset > node.size {
             return Ok(0);
         };
 
+        if node.is_inline() {
+            let end_pos = offset + data.len();
+            if end_pos <= INLINE_MAX {
+                node.inline_data_mut()[offset..end_pos].copy_from_slice(data);
+                node.size = node.size.max(end_pos);
+                node.set_mtime(self.now);
+                self.write_node(node_ptr, node)?;
+                return Ok(data.len());
+            }
+            self.uninline(node_ptr, reservation.as_deref_mut())?;
+            node = self.read_node(node_ptr)?;
+        }
+
         let bytes_to_write = data.len();
         let mut bytes_written = 0;
         let mut node_updated = false;
@@ -171,18 +645,37 @@ impl<'a> Transaction<'a> {
             let curr_pos = offset + bytes_written;
             let offset_in_block = curr_pos % BLOCK_SIZE; // First read might be unaligned
             let block_offset = Node::get_block_offset_from_offset(curr_pos);
+            let chunk_size = (BLOCK_SIZE - offset_in_block).min(bytes_to_write - bytes_written);
             let (block_id, has_alloc) = match node.get_block_id(block_offset) {
                 Some(block_id) => (block_id, false),
+                // Keep whole zero blocks as holes, never punching allocated ones
+                None if self.sparse
+                    && chunk_size == BLOCK_SIZE
+                    && data[bytes_written..(bytes_written + chunk_size)]
+                        .iter()
+                        .all(|&b| b == 0) =>
+                {
+                    bytes_written += chunk_size;
+                    continue;
+                }
                 None => {
-                    // Allocate a block
-                    let (block_id, _) = self.fs.block_map.allocate(1).map_err(Error::Alloc)?;
-                    node.map_block(block_offset, block_id)
-                        .map_err(Error::Node)?;
+                    // Once some data is written, running out of space ends the write short
+                    let block_id = match self.allocate_block(reservation.as_deref_mut()) {
+                        Ok(block_id) => block_id,
+                        Err(e) if bytes_written == 0 || !short => return Err(e),
+                        Err(_) => break,
+                    };
+                    if let Err(e) = node.map_block(block_offset, block_id) {
+                        self.free_blocks(&[block_id])?;
+                        if bytes_written == 0 || !short {
+                            return Err(Error::Node(e));
+                        }
+                        break;
+                    }
                     node_updated = true;
                     (block_id, true)
                 }
             };
-            let chunk_size = (BLOCK_SIZE - offset_in_block).min(bytes_to_write - bytes_written);
             // Don't need to read if it's a freshly allocated block
             let mut block = if has_alloc {
                 Block::default()
@@ -200,6 +693,10 @@ impl<'a> Transaction<'a> {
             node.size = end_pos;
             node_updated = true;
         }
+        if bytes_written > 0 && node.mtime() != self.now {
+            node.set_mtime(self.now);
+            node_updated = true;
+        }
 
         if node_updated {
             self.write_node(node_ptr, node)?;
@@ -208,53 +705,306 @@ impl<'a> Transaction<'a> {
         Ok(bytes_written)
     }
 
-    /// Truncates the size of the file to `size`.
-    pub fn truncate_file(&mut self, node_ptr: NodePtr, size: usize) -> Result<()> {
+    /// Moves the inline contents of the node into a block of its own,
+    /// so that it can grow past [INLINE_MAX].
+    fn uninline(&mut self, node_ptr: NodePtr, reservation: Option<&mut Reservation>) -> Result<()> {
         let mut node = self.read_node(node_ptr)?;
+        let data = node.inline_data().to_vec();
+        node.set_inline(false);
+        if !data.is_empty() {
+            let block_id = self.allocate_block(reservation)?;
+            node.map_block(0, block_id).map_err(Error::Node)?;
+            self.write_block(block_id, &Block::new(&data));
+        }
+        self.write_node(node_ptr, node)
+    }
 
+    /// Checks whether the blocks in `block_offsets` could be mapped into the node
+    /// without running out of extents.
+    ///
+    /// Unmapped blocks are assumed to be allocated contiguously after the last mapped one,
+    /// which is the best case: `false` means the write is certain to run out of extents.
+    pub fn can_map(node: &Node, block_offsets: Range<usize>) -> bool {
+        let mut node = *node;
+        // Growing an inline node moves its contents into fresh extents
+        if node.is_inline() {
+            node.set_inline(false);
+        }
+        // Past the end of any mapped block, so the assumed ids never collide
+        let mut next_id = node
+            .get_extents()
+            .iter()
+            .filter(|e| !e.is_hole())
+            .map(|e| e.end())
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        for block_offset in block_offsets {
+            if node.get_block_id(block_offset).is_some() {
+                continue;
+            }
+            if node.map_block(block_offset, next_id).is_err() {
+                return false;
+            }
+            next_id += 1;
+        }
+        true
+    }
+
+    /// Writes the whole byte slice to the file, failing if the write would be short.
+    pub fn write_file_all(&mut self, node_ptr: NodePtr, offset: usize, data: &[u8]) -> Result<()> {
+        // Only a write starting past the end of the file can still be short
+        if self._write_file_at(node_ptr, offset, data, None, false)? < data.len() {
+            return Err(Error::NotPermitted);
+        }
+        Ok(())
+    }
+
+    /// Copies up to `len` bytes from the file at `src_offset` to another file at `dst_offset`,
+    /// a block at a time. Returns the number of bytes copied,
+    /// which is short at the end of the source or if the destination can't grow further.
+    ///
+    /// Whole blocks that are holes in the source stay holes in the destination,
+    /// as long as they line up with unallocated blocks there.
+    pub fn copy_file_range(
+        &mut self,
+        src_ptr: NodePtr,
+        src_offset: usize,
+        dst_ptr: NodePtr,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<usize> {
+        let mut buf = [0u8; BLOCK_SIZE];
+        let mut copied = 0;
+        while copied < len {
+            let (src_pos, dst_pos) = (src_offset + copied, dst_offset + copied);
+            // Chunks end on source block boundaries, so a chunk is either a hole or not
+            let chunk_size = (BLOCK_SIZE - src_pos % BLOCK_SIZE).min(len - copied);
+            let bytes_read = self.read_file_at(src_ptr, src_pos, &mut buf[..chunk_size])?;
+            if bytes_read == 0 {
+                break;
+            }
+
+            let src = self.read_node(src_ptr)?;
+            let dst = self.read_node(dst_ptr)?;
+            let is_hole = !src.is_inline() && src.get_block_id_from_offset(src_pos).is_none();
+            let fits_hole = bytes_read == BLOCK_SIZE
+                && dst_pos.is_multiple_of(BLOCK_SIZE)
+                && dst_pos <= dst.size
+                && !dst.is_inline()
+                && dst.get_block_id_from_offset(dst_pos).is_none();
+            if is_hole && fits_hole {
+                if dst_pos + bytes_read > dst.size {
+                    self.truncate_file(dst_ptr, dst_pos + bytes_read)?;
+                }
+                copied += bytes_read;
+                continue;
+            }
+
+            let bytes_written = self.write_file_at(dst_ptr, dst_pos, &buf[..bytes_read])?;
+            copied += bytes_written;
+            if bytes_written < bytes_read {
+                break;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Allocates a single block, taking it from `reservation` if possible.
+    ///
+    /// An empty reservation is refilled with a span of preferred size,
+    /// falling back to a single block when no such span is free.
+    fn allocate_block(&mut self, reservation: Option<&mut Reservation>) -> Result<usize> {
+        let Some(reservation) = reservation else {
+            let (block_id, _) = self.fs.block_map.allocate(1).map_err(Error::Alloc)?;
+            return Ok(block_id);
+        };
+        if reservation.is_empty()
+            && reservation.preferred_extent > 1
+            && let Ok(span) = self.fs.block_map.allocate(reservation.preferred_extent)
+        {
+            reservation.span = span;
+        }
+        if !reservation.is_empty() {
+            let block_id = reservation.span.0;
+            reservation.span.0 += 1;
+            return Ok(block_id);
+        }
+        let (block_id, _) = self.fs.block_map.allocate(1).map_err(Error::Alloc)?;
+        Ok(block_id)
+    }
+
+    /// Frees the unused blocks of `reservation`.
+    pub fn release_reservation(&mut self, reservation: &mut Reservation) -> Result<()> {
+        if !reservation.is_empty() {
+            self.fs
+                .block_map
+                .free(reservation.span)
+                .map_err(Error::Alloc)?;
+        }
+        reservation.span = (0, 0);
+        Ok(())
+    }
+
+    /// Marks individual blocks as free in the block map.
+    fn free_blocks(&mut self, block_ids: &[usize]) -> Result<()> {
+        for &id in block_ids {
+            self.fs.block_map.free((id, id + 1)).map_err(Error::Alloc)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the access and modification times of the node, in seconds since the Unix epoch.
+    pub fn set_times(&mut self, node_ptr: NodePtr, atime: u64, mtime: u64) -> Result<()> {
+        let mut node = self.read_node(node_ptr)?;
+        node.set_atime(atime);
+        node.set_mtime(mtime);
+        self.write_node(node_ptr, node)
+    }
+
+    /// Truncates the size of the file to `size`.
+    pub fn truncate_file(&mut self, node_ptr: NodePtr, size: usize) -> Result<()> {
+        let node = self.read_node(node_ptr)?;
         if node.filetype() != FileType::File {
             return Err(Error::NotFile);
         }
+        self.truncate_node(node_ptr, size)
+    }
+
+    /// Truncates the size of the node to `size`, regardless of its filetype.
+    ///
+    /// Bytes past the end of the node within its last block are kept zeroed,
+    /// so that growing the node never exposes stale data, such as removed directory entries.
+    fn truncate_node(&mut self, node_ptr: NodePtr, size: usize) -> Result<()> {
+        let mut node = self.read_node(node_ptr)?;
+
+        if node.is_inline() {
+            if size <= INLINE_MAX {
+                let old_size = node.size;
+                if size < old_size {
+                    node.inline_data_mut()[size..old_size].fill(0u8);
+                }
+                node.size = size;
+                node.set_mtime(self.now);
+                self.write_node(node_ptr, node)?;
+                return Ok(());
+            }
+            self.uninline(node_ptr, None)?;
+            node = self.read_node(node_ptr)?;
+        }
 
         if size >= node.size {
+            let block_end = node.size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+            self.zero_range(&node, node.size, size.min(block_end))?;
             node.size = size;
+            node.set_mtime(self.now);
             self.write_node(node_ptr, node)?;
             return Ok(());
         }
 
         let blocks_needed = size.div_ceil(BLOCK_SIZE);
+        self.zero_range(&node, size, node.size.min(blocks_needed * BLOCK_SIZE))?;
         let mut blocks_passed = 0;
         for extent in node.get_mut_extents() {
             if extent.is_null() {
                 break;
             }
             let extent_len = extent.len();
+            // Holes don't own any blocks
+            let is_hole = extent.is_hole();
             if blocks_passed >= blocks_needed {
                 // Extent is entirely beyond the size
-                self.fs
-                    .block_map
-                    .free(extent.span())
-                    .map_err(Error::Alloc)?;
+                if !is_hole {
+                    self.fs
+                        .block_map
+                        .free(extent.span())
+                        .map_err(Error::Alloc)?;
+                }
                 extent.nullify();
-            } else if blocks_passed + extent_len >= blocks_needed {
+            } else if blocks_passed + extent_len > blocks_needed {
                 // Extent is partially needed
                 let blocks_keep = blocks_needed - blocks_passed;
                 let new_end = extent.start() + blocks_keep;
-                self.fs
-                    .block_map
-                    .free((new_end, extent.end()))
-                    .map_err(Error::Alloc)?;
+                if !is_hole {
+                    self.fs
+                        .block_map
+                        .free((new_end, extent.end()))
+                        .map_err(Error::Alloc)?;
+                }
                 extent.shrink(blocks_keep);
             }
             blocks_passed += extent_len;
         }
 
         node.size = size;
+        node.set_mtime(self.now);
         self.write_node(node_ptr, node)?;
         Ok(())
     }
 
+    /// Deallocates the blocks of the file that lie entirely within `[offset, offset + len)`,
+    /// turning them into a hole. Partially covered blocks are zeroed instead.
+    /// The size of the file is left unchanged.
+    pub fn punch_hole(&mut self, node_ptr: NodePtr, offset: usize, len: usize) -> Result<()> {
+        let mut node = self.read_node(node_ptr)?;
+        if node.filetype() != FileType::File {
+            return Err(Error::NotFile);
+        }
+
+        let end = (offset + len).min(node.size);
+        if offset >= end {
+            return Ok(());
+        }
+
+        if node.is_inline() {
+            node.inline_data_mut()[offset..end].fill(0u8);
+            node.set_mtime(self.now);
+            return self.write_node(node_ptr, node);
+        }
+
+        let block_start = offset.div_ceil(BLOCK_SIZE);
+        // The last block can be dropped whole if the range reaches the end of the file
+        let block_end = if end == node.size {
+            end.div_ceil(BLOCK_SIZE)
+        } else {
+            end / BLOCK_SIZE
+        };
+
+        if block_start >= block_end {
+            return self.zero_range(&node, offset, end);
+        }
+        self.zero_range(&node, offset, block_start * BLOCK_SIZE)?;
+        self.zero_range(&node, (block_end * BLOCK_SIZE).min(end), end)?;
+
+        let spans = node
+            .unmap_blocks(block_start, block_end)
+            .map_err(Error::Node)?;
+        for span in spans {
+            self.fs.block_map.free(span).map_err(Error::Alloc)?;
+        }
+        node.set_mtime(self.now);
+        self.write_node(node_ptr, node)
+    }
+
+    /// Zeroes the bytes of the file in `[start, end)` that are backed by blocks.
+    fn zero_range(&mut self, node: &Node, start: usize, end: usize) -> Result<()> {
+        let mut pos = start;
+        while pos < end {
+            let offset_in_block = pos % BLOCK_SIZE;
+            let chunk_size = (BLOCK_SIZE - offset_in_block).min(end - pos);
+            if let Some(block_id) = node.get_block_id_from_offset(pos) {
+                let mut block = self.read_block(block_id)?;
+                block.data[offset_in_block..(offset_in_block + chunk_size)].fill(0u8);
+                self.write_block(block_id, &block);
+            }
+            pos += chunk_size;
+        }
+        Ok(())
+    }
+
     /// Creates a file with given name and type inside `parent_ptr`.
+    /// Its node is allocated as close to the parent's node as possible,
+    /// rather than at the lowest free id.
     /// Returns the file's node pointer.
     pub fn create_file(
         &mut self,
@@ -269,15 +1019,48 @@ impl<'a> Transaction<'a> {
             return Err(Error::FileExists);
         }
 
-        let (mut node, node_ptr) = self.create_node(filetype)?;
+        // Keep nodes of a directory's files close to it
+        let (mut node, node_ptr) = self.create_node_near(filetype, parent_ptr)?;
         node.link_count += 1;
 
         let entry = DirEntry::new(node_ptr, filetype, name);
-        parent.add_entry(entry);
+        let slot = parent.add_entry(entry);
+
+        self.write_dir_entry(parent_ptr, slot, &entry)?;
+        self.write_node(node_ptr, node)?;
+
+        Ok(node_ptr)
+    }
+
+    /// Creates a regular file with given name inside `parent_ptr`, filled with `data`.
+    /// The file is linked into the parent only after its contents are written.
+    /// Its node is allocated as close to the parent's node as possible,
+    /// rather than at the lowest free id.
+    /// Returns the file's node pointer.
+    pub fn create_file_with(
+        &mut self,
+        parent_ptr: NodePtr,
+        name: &str,
+        data: &[u8],
+    ) -> Result<NodePtr> {
+        let name = DirEntryName::try_from(name).map_err(Error::Dir)?;
+
+        let mut parent = self.read_directory(parent_ptr)?;
+        if parent.get_entry(name).is_some() {
+            return Err(Error::FileExists);
+        }
+
+        let (_, node_ptr) = self.create_node_near(FileType::File, parent_ptr)?;
+        self.write_file_all(node_ptr, 0, data)?;
 
-        self.write_directory(parent_ptr, &parent)?;
+        let mut node = self.read_node(node_ptr)?;
+        node.link_count += 1;
         self.write_node(node_ptr, node)?;
 
+        let entry = DirEntry::new(node_ptr, FileType::File, name);
+        let slot = parent.add_entry(entry);
+        self.write_dir_entry(parent_ptr, slot, &entry)?;
+
         Ok(node_ptr)
     }
 
@@ -296,8 +1079,36 @@ impl<'a> Transaction<'a> {
     /// Writes the directory.
     pub fn write_directory(&mut self, node_ptr: NodePtr, dir: &Dir) -> Result<()> {
         let bytes = dir.as_slice().as_bytes();
-        self.write_file_at(node_ptr, 0, bytes)?;
-        Ok(())
+        self.write_file_all(node_ptr, 0, bytes)
+    }
+
+    /// Writes a single directory entry into the slot at `slot`,
+    /// touching only the blocks the entry spans instead of rewriting the whole directory.
+    /// The slot may be one past the last, which appends the entry.
+    pub fn write_dir_entry(
+        &mut self,
+        node_ptr: NodePtr,
+        slot: usize,
+        entry: &DirEntry,
+    ) -> Result<()> {
+        let node = self.read_node(node_ptr)?;
+        if node.filetype() != FileType::Dir {
+            return Err(Error::NotDir);
+        }
+        let offset = slot * size_of::<DirEntry>();
+        if offset > node.size {
+            return Err(Error::CorruptedDir);
+        }
+        self.write_file_all(node_ptr, offset, entry.as_bytes())
+    }
+
+    /// Rewrites the directory without its null entries, reclaiming unused blocks.
+    pub fn compact_directory(&mut self, node_ptr: NodePtr) -> Result<()> {
+        let mut dir = self.read_directory(node_ptr)?;
+        dir.compact();
+        self.write_directory(node_ptr, &dir)?;
+        let size = dir.as_slice().as_bytes().len();
+        self.truncate_node(node_ptr, size)
     }
 
     /// Creates a directory with given name inside `parent_ptr`.
@@ -347,14 +1158,52 @@ impl<'a> Transaction<'a> {
         }
 
         let entry = DirEntry::new(node_ptr, node.filetype(), name);
-        dir.add_entry(entry);
+        let slot = dir.add_entry(entry);
         node.link_count += 1;
 
         self.write_node(node_ptr, node)?;
-        self.write_directory(parent_ptr, &dir)?;
+        self.write_dir_entry(parent_ptr, slot, &entry)?;
         Ok(())
     }
 
+    /// Creates hard links inside `dst_ptr` to every non-directory entry of `src_ptr`.
+    /// If `recursive` is true, subdirectories are recreated inside `dst_ptr` and linked as well.
+    /// Returns the number of links created.
+    pub fn link_tree(
+        &mut self,
+        src_ptr: NodePtr,
+        dst_ptr: NodePtr,
+        recursive: bool,
+    ) -> Result<usize> {
+        // The top-level destination is skipped if it lies inside the source
+        let root_ptr = dst_ptr;
+        let mut visited = Visited::new();
+        let mut count = 0;
+        // Directories left to link, each with the directory its links go into.
+        // An explicit stack keeps deeply nested trees from overflowing the call stack.
+        let mut stack = vec![(src_ptr, dst_ptr)];
+        while let Some((src_ptr, dst_ptr)) = stack.pop() {
+            visit(&mut visited, src_ptr)?;
+            let src = self.read_directory(src_ptr)?;
+            for entry in src.as_slice().iter().filter(|e| !e.is_null()) {
+                let name = entry.name()?;
+                if name == "." || name == ".." || entry.node_ptr() == root_ptr {
+                    continue;
+                }
+                if entry.filetype() == FileType::Dir {
+                    if recursive {
+                        let sub_ptr = self.create_directory(dst_ptr, name)?;
+                        stack.push((entry.node_ptr(), sub_ptr));
+                    }
+                    continue;
+                }
+                self.link_file(dst_ptr, entry.node_ptr(), name)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
     /// Removes a hard link to the file with a given name.
     /// If `free` is true, deletes the node if `node.link_count` drops to 0, else it must be deallocated manually.
     pub fn unlink_file(&mut self, parent_ptr: NodePtr, name: &str, free: bool) -> Result<()> {
@@ -366,9 +1215,15 @@ impl<'a> Transaction<'a> {
             return Err(Error::IsDir);
         }
 
+        let slot = dir.get_slot(name).ok_or(Error::NodeNotFound)?;
         let node_ptr = dir.remove_entry(name).map_err(Error::Dir)?;
-        self.write_directory(parent_ptr, &dir)?;
+        self.write_dir_entry(parent_ptr, slot, &dir.as_slice()[slot])?;
+        self.drop_link(node_ptr, free)
+    }
 
+    /// Decrements the `link_count` of the node after one of its entries is gone.
+    /// If `free` is true, deletes the node if `link_count` drops to 0.
+    fn drop_link(&mut self, node_ptr: NodePtr, free: bool) -> Result<()> {
         let mut node = self.read_node(node_ptr)?;
         node.link_count -= 1;
 
@@ -381,6 +1236,96 @@ impl<'a> Transaction<'a> {
         Ok(())
     }
 
+    /// Moves the entry `src_name` of `src_parent` to `dst_name` inside `dst_parent`,
+    /// replacing an existing non-directory target.
+    /// If `free` is true, the replaced target is deleted if its `link_count` drops to 0.
+    ///
+    /// The source isn't staged under a hidden temporary name, as such a name could collide
+    /// with a user's file. Instead, an existing target's entry is overwritten in place to point
+    /// at the source. The entry is a single slot written in one transaction, so this is just as
+    /// atomic: the destination always holds either the old or the new entry, never neither.
+    pub fn rename(
+        &mut self,
+        src_parent: NodePtr,
+        src_name: &str,
+        dst_parent: NodePtr,
+        dst_name: &str,
+        free: bool,
+    ) -> Result<()> {
+        let is_special = |name: &str| name == "." || name == "..";
+        if is_special(src_name) || is_special(dst_name) {
+            return Err(Error::NotPermitted);
+        }
+        let new_name = DirEntryName::try_from(dst_name)?;
+        let entry = self.find_entry(src_parent, src_name)?;
+        let node_ptr = entry.node_ptr();
+        let target = match self.find_entry(dst_parent, dst_name) {
+            Ok(target) => Some(target),
+            Err(Error::NodeNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(target) = target {
+            if target.node_ptr() == node_ptr {
+                return Ok(());
+            }
+            if target.filetype() == FileType::Dir {
+                return Err(Error::IsDir);
+            }
+            if entry.filetype() == FileType::Dir {
+                return Err(Error::NotDir);
+            }
+        }
+        // A directory can't be moved inside itself
+        if entry.filetype() == FileType::Dir && self.is_descendant(node_ptr, dst_parent)? {
+            return Err(Error::InvalidMove);
+        }
+
+        let new_entry = DirEntry::new(node_ptr, entry.filetype(), new_name);
+        let mut dst = self.read_directory(dst_parent)?;
+        match dst.get_slot(new_name) {
+            Some(slot) => {
+                self.write_dir_entry(dst_parent, slot, &new_entry)?;
+            }
+            None => {
+                dst.add_entry(new_entry);
+                self.write_directory(dst_parent, &dst)?;
+            }
+        }
+
+        if let Some(target) = target {
+            self.drop_link(target.node_ptr(), free)?;
+        }
+
+        let mut src = self.read_directory(src_parent)?;
+        src.remove_entry(DirEntryName::try_from(src_name)?)?;
+        self.write_directory(src_parent, &src)?;
+
+        if entry.filetype() == FileType::Dir && src_parent != dst_parent {
+            let mut dir = self.read_directory(node_ptr)?;
+            dir.set_parent(dst_parent);
+            self.write_directory(node_ptr, &dir)?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether the directory `maybe_descendant` is `ancestor` or lies anywhere below it.
+    /// Walks up from `maybe_descendant` following `..` entries until `ancestor` or the root,
+    /// which is cheaper than searching the subtree of `ancestor`.
+    pub fn is_descendant(&self, ancestor: NodePtr, maybe_descendant: NodePtr) -> Result<bool> {
+        let mut visited = Visited::new();
+        let mut curr_ptr = maybe_descendant;
+        loop {
+            if curr_ptr == ancestor {
+                return Ok(true);
+            }
+            if curr_ptr == NodePtr::root() {
+                return Ok(false);
+            }
+            visit(&mut visited, curr_ptr)?;
+            curr_ptr = self.find_entry(curr_ptr, "..")?.node_ptr();
+        }
+    }
+
     /// Creates a symlink inside `parent_ptr`, containing `target`.
     /// Returns the node pointer of the symlink.
     pub fn create_symlink(
@@ -390,14 +1335,49 @@ impl<'a> Transaction<'a> {
         target: &Path,
     ) -> Result<NodePtr> {
         let node_ptr = self.create_file(parent_ptr, name, FileType::Symlink)?;
-        self.write_file_at(node_ptr, 0, target.as_bytes())?;
+        self.write_file_all(node_ptr, 0, target.as_bytes())?;
         Ok(node_ptr)
     }
 
+    /// Returns the extended attributes of the node.
+    pub fn read_xattrs(&self, node_ptr: NodePtr) -> Result<Xattrs> {
+        let node = self.read_node(node_ptr)?;
+        if node.xattr_block == 0 {
+            return Ok(Xattrs::default());
+        }
+        let block = self.read_block(node.xattr_block)?;
+        Xattrs::from_block(&block).map_err(Error::Xattr)
+    }
+
+    /// Sets the extended attribute `name` of the node to `value`.
+    /// The attribute block is allocated on first use.
+    pub fn set_xattr(&mut self, node_ptr: NodePtr, name: &str, value: &[u8]) -> Result<()> {
+        let mut xattrs = self.read_xattrs(node_ptr)?;
+        xattrs.set(name, value).map_err(Error::Xattr)?;
+
+        let mut node = self.read_node(node_ptr)?;
+        if node.xattr_block == 0 {
+            let (block_id, _) = self.fs.block_map.allocate(1).map_err(Error::Alloc)?;
+            node.xattr_block = block_id;
+            self.write_node(node_ptr, node)?;
+        }
+        self.write_block(node.xattr_block, &xattrs.to_block());
+        Ok(())
+    }
+
     /// Removes the node, deallocating its blocks.
     pub fn remove_node(&mut self, node_ptr: NodePtr) -> Result<()> {
         let node = self.read_node(node_ptr)?;
-        let extents = node.get_extents().iter().take_while(|e| !e.is_null());
+        if node.xattr_block != 0 {
+            let id = node.xattr_block;
+            self.fs.block_map.free((id, id + 1)).map_err(Error::Alloc)?;
+        }
+        // Holes don't own any blocks
+        let extents = node
+            .get_extents()
+            .iter()
+            .take_while(|e| !e.is_null())
+            .filter(|e| !e.is_hole());
         for extent in extents {
             self.fs
                 .block_map
@@ -411,6 +1391,105 @@ impl<'a> Transaction<'a> {
         Ok(())
     }
 
+    /// Moves every entry of the directory `src_name` in `src_parent` into the directory `dst_ptr`,
+    /// then removes the emptied source directory. Moved directories get their `..` fixed.
+    ///
+    /// A name taken in the destination fails the merge with [Error::FileExists],
+    /// unless `overwrite` is set, in which case the moved entry replaces it like [Transaction::rename].
+    /// Replaced nodes in `opened` keep their blocks after losing their last link.
+    pub fn merge_directories(
+        &mut self,
+        src_parent: NodePtr,
+        src_name: &str,
+        dst_ptr: NodePtr,
+        overwrite: bool,
+        opened: &[NodePtr],
+    ) -> Result<()> {
+        let src = self.find_entry(src_parent, src_name)?;
+        if src.filetype() != FileType::Dir {
+            return Err(Error::NotDir);
+        }
+        let src_ptr = src.node_ptr();
+        if self.read_node(dst_ptr)?.filetype() != FileType::Dir {
+            return Err(Error::NotDir);
+        }
+        // The destination can't be the source or lie inside it
+        if self.is_descendant(src_ptr, dst_ptr)? {
+            return Err(Error::InvalidMove);
+        }
+
+        let dir = self.read_directory(src_ptr)?;
+        let mut names = Vec::new();
+        for entry in dir.as_slice().iter().filter(|e| !e.is_null()) {
+            let name = entry.name()?;
+            if name != "." && name != ".." {
+                names.push(name.to_string());
+            }
+        }
+        for name in names {
+            let target = match self.find_entry(dst_ptr, &name) {
+                Ok(target) => Some(target),
+                Err(Error::NodeNotFound) => None,
+                Err(e) => return Err(e),
+            };
+            if target.is_some() && !overwrite {
+                return Err(Error::FileExists);
+            }
+            let free = target.is_none_or(|t| !opened.contains(&t.node_ptr()));
+            self.rename(src_ptr, &name, dst_ptr, &name, free)?;
+        }
+        self.remove_directory(src_parent, src_name)
+    }
+
+    /// Relocates allocated nodes to the lowest free ids, keeping their relative order,
+    /// and points every directory entry, including `.` and `..`, at the new ids.
+    /// Returns the `(old, new)` id pairs of the moved nodes.
+    pub fn compact_nodes(&mut self) -> Result<Vec<(usize, usize)>> {
+        let live: Vec<usize> = self.fs.node_map.used_ids().filter(|&id| id != 0).collect();
+        // The n-th live node moves to id n, which is never above its id,
+        // so moving in ascending order never overwrites a node that hasn't moved yet
+        let moves: Vec<(usize, usize)> = live
+            .into_iter()
+            .zip(1..)
+            .filter(|(old, new)| old != new)
+            .collect();
+        if moves.is_empty() {
+            return Ok(moves);
+        }
+        for &(old, new) in &moves {
+            let node = self.read_node(NodePtr::new(old))?;
+            self.fs.node_map.allocate_at(new).map_err(Error::Alloc)?;
+            self.write_node(NodePtr::new(new), node)?;
+            self.fs
+                .node_map
+                .free((old, old + 1))
+                .map_err(Error::Alloc)?;
+            self.write_node(NodePtr::new(old), Node::default())?;
+        }
+
+        let new_ids: BTreeMap<usize, usize> = moves.iter().copied().collect();
+        let dirs: Vec<NodePtr> = self
+            .list_nodes()?
+            .into_iter()
+            .filter(|(_, node)| node.filetype() == FileType::Dir)
+            .map(|(node_ptr, _)| node_ptr)
+            .collect();
+        for dir_ptr in dirs {
+            let mut dir = self.read_directory(dir_ptr)?;
+            let mut changed = false;
+            for entry in dir.as_mut_slice().iter_mut().filter(|e| !e.is_null()) {
+                if let Some(&new) = new_ids.get(&entry.node_ptr().id()) {
+                    entry.set_node_ptr(NodePtr::new(new));
+                    changed = true;
+                }
+            }
+            if changed {
+                self.write_directory(dir_ptr, &dir)?;
+            }
+        }
+        Ok(moves)
+    }
+
     /// Returns the path contained inside `symlink_ptr`.
     pub fn read_symlink(&self, symlink_ptr: NodePtr) -> Result<Path<'_>> {
         let node = self.read_node(symlink_ptr)?;
@@ -422,6 +1501,146 @@ impl<'a> Transaction<'a> {
         Ok(Path::try_from_bytes_owned(&buf)?)
     }
 
+    /// Walks the directory tree under `root_ptr` depth-first, in pre-order.
+    /// Calls `f` with the path (relative to `root_ptr`) and the entry of every node, skipping `.` and `..`.
+    /// Symlinks are not followed.
+    pub fn walk(
+        &self,
+        root_ptr: NodePtr,
+        mut f: impl FnMut(&str, &DirEntry) -> Result<()>,
+    ) -> Result<()> {
+        let mut visited = Visited::new();
+        let mut stack = Vec::new();
+        self.push_children(&mut stack, &mut visited, root_ptr, "")?;
+        while let Some((path, entry)) = stack.pop() {
+            f(&path, &entry)?;
+            if entry.filetype() == FileType::Dir {
+                self.push_children(&mut stack, &mut visited, entry.node_ptr(), &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Pushes the entries of a directory onto the 'walk' stack, so that they pop in order.
+    fn push_children(
+        &self,
+        stack: &mut Vec<(String, DirEntry)>,
+        visited: &mut Visited,
+        dir_ptr: NodePtr,
+        dir_path: &str,
+    ) -> Result<()> {
+        visit(visited, dir_ptr)?;
+        let dir = self.read_directory(dir_ptr)?;
+        for entry in dir.as_slice().iter().rev().filter(|e| !e.is_null()) {
+            let name = entry.name()?;
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = if dir_path.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", dir_path, name)
+            };
+            stack.push((path, *entry));
+        }
+        Ok(())
+    }
+
+    /// Finds the node whose extents or extended attributes reference the block.
+    /// Returns `None` for free and metadata blocks.
+    pub fn block_owner(&self, block_id: usize) -> Result<Option<NodePtr>> {
+        if block_id >= self.fs.superblock.block_count {
+            return Err(Error::BlockIdOutOfBounds);
+        }
+        if block_id < self.fs.superblock.data_start || !self.fs.block_map.is_used(block_id) {
+            return Ok(None);
+        }
+        let owner = self.list_nodes()?.into_iter().find(|(_, node)| {
+            node.xattr_block == block_id
+                || node.get_extents().iter().any(|e| {
+                    !e.is_null() && !e.is_hole() && (e.start()..e.end()).contains(&block_id)
+                })
+        });
+        Ok(owner.map(|(node_ptr, _)| node_ptr))
+    }
+
+    /// Finds the absolute paths of every directory entry that references `node_ptr`.
+    pub fn find_links(&self, node_ptr: NodePtr) -> Result<Vec<String>> {
+        let mut links = Vec::new();
+        self.walk(NodePtr::root(), |path, entry| {
+            if entry.node_ptr() == node_ptr {
+                links.push(format!("/{}", path));
+            }
+            Ok(())
+        })?;
+        Ok(links)
+    }
+
+    /// Finds allocated nodes that are not referenced by any directory entry reachable from the root.
+    pub fn find_orphans(&self) -> Result<Vec<NodePtr>> {
+        let referenced = self.reachable_ids()?;
+        Ok(self
+            .fs
+            .node_map
+            .used_ids()
+            .filter(|id| *id != 0 && !referenced.contains(id))
+            .map(NodePtr::new)
+            .collect())
+    }
+
+    /// Returns ids of the nodes reachable by walking the directory tree from the root,
+    /// including the root itself.
+    pub fn reachable_ids(&self) -> Result<BTreeSet<usize>> {
+        let mut reachable = BTreeSet::from([NodePtr::root().id()]);
+        self.walk(NodePtr::root(), |_, entry| {
+            reachable.insert(entry.node_ptr().id());
+            Ok(())
+        })?;
+        Ok(reachable)
+    }
+
+    /// Links the regular files among `orphans` into the `lost+found` directory under the root,
+    /// creating it if needed. Each one is named `node_<id>` and is left with a single link.
+    /// Returns the pointers of the recovered nodes.
+    pub fn recover_orphans(&mut self, orphans: &[NodePtr]) -> Result<Vec<NodePtr>> {
+        const LOST_FOUND: &str = "lost+found";
+
+        let ids: Vec<usize> = orphans
+            .iter()
+            .map(|p| p.id())
+            .filter(|&id| self.fs.node_map.is_used(id))
+            .collect();
+        // Recovering a node doesn't touch the others, so they can be read up front
+        let nodes = self.read_nodes(&ids)?;
+
+        let mut recovered = Vec::new();
+        for (node_ptr, mut node) in ids.into_iter().map(NodePtr::new).zip(nodes) {
+            if node.filetype() != FileType::File {
+                continue;
+            }
+
+            let lost_found = match self.find_entry(NodePtr::root(), LOST_FOUND) {
+                Ok(entry) => entry.node_ptr(),
+                Err(Error::NodeNotFound) => self.create_directory(NodePtr::root(), LOST_FOUND)?,
+                Err(e) => return Err(e),
+            };
+
+            let name = format!("node_{}", node_ptr.id());
+            let name = DirEntryName::try_from(name.as_str()).map_err(Error::Dir)?;
+            let mut dir = self.read_directory(lost_found)?;
+            if dir.get_entry(name).is_some() {
+                return Err(Error::FileExists);
+            }
+            dir.add_entry(DirEntry::new(node_ptr, FileType::File, name));
+            self.write_directory(lost_found, &dir)?;
+
+            node.link_count = 1;
+            self.write_node(node_ptr, node)?;
+            recovered.push(node_ptr);
+        }
+        Ok(recovered)
+    }
+
     /// Finds the entry named `name` inside `parent_ptr`.
     pub fn find_entry(&self, parent_ptr: NodePtr, name: &str) -> Result<DirEntry> {
         let name = DirEntryName::try_from(name)?;
@@ -430,34 +1649,78 @@ impl<'a> Transaction<'a> {
     }
 
     /// Finds the node at `path`, using `start_node_ptr` as the start if `path` is relative.
+    /// Symlinks are followed, and a relative target is resolved against the directory
+    /// containing the link, not against `start_node_ptr`.
     pub fn path_node(&self, path: &Path, start_node_ptr: NodePtr) -> Result<NodePtr> {
-        self._path_node(path, start_node_ptr, 0)
+        self._path_node(path, start_node_ptr, 0, None)
+    }
+
+    /// Resolves `path` like `path_node`, recording a [ResolveStep] for each component visited.
+    /// Components of followed symlinks are recorded right after the symlink's own step.
+    pub fn resolve_trace(&self, path: &Path, start_node_ptr: NodePtr) -> Result<Vec<ResolveStep>> {
+        let mut trace = Vec::new();
+        self._path_node(path, start_node_ptr, 0, Some(&mut trace))?;
+        Ok(trace)
+    }
+
+    /// Finds the node at `path` like `path_node`, but doesn't follow the final component if it's a symlink.
+    pub fn path_node_nofollow(&self, path: &Path, start_node_ptr: NodePtr) -> Result<NodePtr> {
+        let Some((parent, name)) = path.split_last() else {
+            return self.path_node(path, start_node_ptr);
+        };
+        let parent_ptr = self.path_node(&parent, start_node_ptr)?;
+        Ok(self.find_entry(parent_ptr, &name)?.node_ptr())
     }
 
     /// Internal implementation of the `path_node` function.
     /// `depth` describes how deep into the recursive call chain the function is.
-    fn _path_node(&self, path: &Path, start_node_ptr: NodePtr, depth: usize) -> Result<NodePtr> {
+    /// If `trace` is given, a [ResolveStep] is pushed to it for each component.
+    fn _path_node(
+        &self,
+        path: &Path,
+        start_node_ptr: NodePtr,
+        depth: usize,
+        mut trace: Option<&mut Vec<ResolveStep>>,
+    ) -> Result<NodePtr> {
         const MAX_DEPTH: usize = 16;
         if depth >= MAX_DEPTH {
             return Err(Error::TooManySymlinks);
         }
 
         let mut curr_node_ptr = start_node_ptr;
-        for part in path.as_parts() {
-            match part.as_ref() {
-                "/" => {
+        for component in path::normalize(path, None) {
+            let name = match component {
+                Component::Root => {
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.push(ResolveStep {
+                            name: String::from("/"),
+                            dir_id: curr_node_ptr.id(),
+                            node_id: NodePtr::root().id(),
+                            is_symlink: false,
+                            depth,
+                        });
+                    }
                     curr_node_ptr = NodePtr::root();
                     continue;
                 }
-                "." => {
-                    continue;
-                }
-                _ => (),
+                Component::Parent => Part::Borrowed(".."),
+                Component::Normal(name) => name,
+            };
+            let entry = self.find_entry(curr_node_ptr, &name)?;
+            let is_symlink = entry.filetype() == FileType::Symlink;
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.push(ResolveStep {
+                    name: name.to_string(),
+                    dir_id: curr_node_ptr.id(),
+                    node_id: entry.node_ptr().id(),
+                    is_symlink,
+                    depth,
+                });
             }
-            let entry = self.find_entry(curr_node_ptr, part.as_ref())?;
-            curr_node_ptr = if entry.filetype() == FileType::Symlink {
+            curr_node_ptr = if is_symlink {
+                // `curr_node_ptr` is still the directory holding the link
                 let target = self.read_symlink(entry.node_ptr())?;
-                self._path_node(&target, curr_node_ptr, depth + 1)?
+                self._path_node(&target, curr_node_ptr, depth + 1, trace.as_deref_mut())?
             } else {
                 entry.node_ptr()
             }
@@ -467,13 +1730,11 @@ impl<'a> Transaction<'a> {
 
     // Internal implementation of 'read_block'.
     // Separated to split borrows in some contexts.
-    fn _read_block(storage: &Storage, changes: &Changes, block_id: usize) -> Result<Block> {
+    fn _read_block(storage: &dyn BlockDevice, changes: &Changes, block_id: usize) -> Result<Block> {
         // Check cached changes
         match changes.get(&block_id) {
             Some(block) => Ok(*block),
-            None => storage
-                .read_block(block_id)
-                .map_err(|_| Error::BlockIdOutOfBounds),
+            None => storage.read_block(block_id).map_err(Error::Storage),
         }
     }
 
@@ -482,6 +1743,18 @@ impl<'a> Transaction<'a> {
         Self::_read_block(self.storage, &self.changes, block_id)
     }
 
+    /// Returns the block like [Transaction::read_block], but borrows it instead of copying
+    /// if it's buffered by the transaction or kept in memory by the storage.
+    pub fn read_block_ref(&self, block_id: usize) -> Result<Cow<'_, Block>> {
+        match self.changes.get(&block_id) {
+            Some(block) => Ok(Cow::Borrowed(block)),
+            None => self
+                .storage
+                .read_block_ref(block_id)
+                .map_err(Error::Storage),
+        }
+    }
+
     // Internal implementation of 'write_block'.
     // Separated to split borrows in some contexts.
     fn _write_block(changes: &mut Changes, block_id: usize, block: &Block) {
@@ -489,8 +1762,68 @@ impl<'a> Transaction<'a> {
     }
 
     /// Queues a write of the block.
+    /// Once more blocks than the flush limit are buffered, they are flushed early.
+    ///
+    /// In write-through mode, the block is written to storage right away instead,
+    /// and only buffered if that fails, so that the commit reports it.
     pub fn write_block(&mut self, block_id: usize, block: &Block) {
+        if self.fs.write_through
+            && !self.fs.crashed
+            && !self.fs.dry_run
+            && self.storage.write_block(block_id, block).is_ok()
+        {
+            self.changes.remove(&block_id);
+            self.flushed.insert(block_id);
+            return;
+        }
         Self::_write_block(&mut self.changes, block_id, block);
+        if self.changes.len() > self.flush_at {
+            self.flush();
+            // Blocks that stayed buffered don't count towards the next flush
+            self.flush_at = self.changes.len() + self.fs.flush_limit;
+        }
+    }
+
+    /// Writes buffered data blocks that are free in the stored block map to storage,
+    /// removing them from the buffer.
+    ///
+    /// Only blocks that no committed node can reference are flushed,
+    /// so an interrupted or aborted transaction leaves the stored filesystem consistent.
+    /// Overwrites of blocks that are already in use stay buffered until the commit,
+    /// so the memory bound doesn't hold for them.
+    /// Blocks that fail to be written stay buffered as well, to be reported by the commit.
+    fn flush(&mut self) {
+        // Blocks of a transaction that is going to be aborted must not reach storage at all
+        if self.fs.crashed || self.fs.dry_run {
+            return;
+        }
+        let superblock = &self.fs.superblock;
+        // Without the stored map, no block is known to be safe to flush
+        let Ok(stored_map) = Filesystem::read_map(
+            self.storage,
+            superblock.block_map_start,
+            superblock.node_map_start,
+            superblock.block_count,
+        ) else {
+            return;
+        };
+        let stored_flags = stored_map.as_slice();
+        let block_ids: Vec<usize> = self
+            .changes
+            .range(superblock.data_start..)
+            .map(|(&id, _)| id)
+            .filter(|&id| stored_flags.get(id) == Some(&AllocFlag::Free))
+            .collect();
+        for block_id in block_ids {
+            if self
+                .storage
+                .write_block(block_id, &self.changes[&block_id])
+                .is_ok()
+            {
+                self.changes.remove(&block_id);
+                self.flushed.insert(block_id);
+            }
+        }
     }
 
     /// Returns the id of the block in which the node resides.
@@ -514,6 +1847,104 @@ impl<'a> Transaction<'a> {
     }
 }
 
+impl Drop for Transaction<'_> {
+    /// Restores the in-memory filesystem state if the transaction didn't commit,
+    /// so allocations of a failed operation don't leak into the next commit.
+    fn drop(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            self.fs.superblock = snapshot.superblock;
+            self.fs.block_map = snapshot.block_map;
+            self.fs.node_map = snapshot.node_map;
+        }
+    }
+}
+
+/// Describes a single step of path resolution.
+#[derive(Debug, Clone)]
+pub struct ResolveStep {
+    /// The path component being resolved.
+    pub name: String,
+    /// Id of the directory node that was searched.
+    pub dir_id: usize,
+    /// Id of the node the component resolved to.
+    pub node_id: usize,
+    /// Whether the resolved node is a symlink that was followed.
+    pub is_symlink: bool,
+    /// How many symlinks deep the step is.
+    pub depth: usize,
+}
+
+/// Contiguous blocks set aside for a file's upcoming writes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Reservation {
+    /// How many blocks to reserve at once.
+    pub preferred_extent: usize,
+    /// The span of reserved blocks not yet mapped to the file.
+    span: (usize, usize),
+}
+
+impl Reservation {
+    /// Constructs an empty [Reservation] of a given preferred size.
+    pub fn new(preferred_extent: usize) -> Self {
+        Self {
+            preferred_extent,
+            span: (0, 0),
+        }
+    }
+
+    /// Returns the number of reserved blocks left.
+    pub fn len(&self) -> usize {
+        self.span.1 - self.span.0
+    }
+
+    /// Checks whether no reserved blocks are left.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Describes what a committed transaction changed.
+#[derive(Debug, Clone)]
+pub struct CommitRecord {
+    /// Ids of the written blocks, in ascending order.
+    pub blocks: Vec<usize>,
+    /// Ids of the nodes whose contents changed, in ascending order.
+    pub nodes: Vec<usize>,
+}
+
+/// Describes the effects of a transaction.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Summary {
+    /// Number of blocks queued for writing, excluding allocation maps.
+    pub blocks_written: usize,
+    pub blocks_allocated: usize,
+    pub blocks_freed: usize,
+    pub nodes_allocated: usize,
+    pub nodes_freed: usize,
+}
+
+/// Describes the blocks that couldn't be written during a commit.
+#[derive(Debug)]
+pub struct CommitError {
+    pub failures: Vec<(usize, storage::Error)>,
+    /// Whether the commit was cut short by a simulated power loss.
+    pub crashed: bool,
+}
+
+/// Where a simulated power loss stops a commit.
+/// Blocks are committed in order: data, allocation maps, node table, superblock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashPoint {
+    /// Only data blocks are written.
+    AfterDataBeforeMeta,
+    /// Data blocks and allocation maps are written, but not the node table.
+    AfterMapsBeforeNodes,
+    /// Everything but the superblock is written.
+    BeforeSuperblock,
+    /// The first half of the blocks, in commit order, is written.
+    AfterHalfBlocks,
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -533,6 +1964,17 @@ pub enum Error {
     FileExists,
     NotSymlink,
     TooManySymlinks,
+    NotPermitted,
+    MapRegionTooSmall,
+    DirectoryCycle,
+    InvalidExtent,
+    Xattr(xattr::Error),
+    InvalidLabel,
+    Unaligned,
+    InvalidMove,
+    InvalidSuperblock,
+    CorruptedMap,
+    Storage(storage::Error),
 }
 
 impl From<directory::Error> for Error {
@@ -0,0 +1,126 @@
+use crate::hardware::storage::block::{BLOCK_SIZE, Block};
+
+/// How long an attribute name can be.
+const NAME_MAX: usize = u8::MAX as usize;
+
+/// Size of a record header: a name length byte followed by a little-endian value length.
+const HEADER_SIZE: usize = 3;
+
+/// Extended attributes of a node, stored in a single block.
+///
+/// The block holds records of `[name_len: u8][value_len: u16][name][value]`,
+/// terminated by a zero name length.
+#[derive(Default)]
+pub struct Xattrs {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Xattrs {
+    /// Returns the value of the attribute `name`.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Sets the attribute `name` to `value`, overwriting the previous value.
+    ///
+    /// # Errors
+    /// Returns `Err` if:
+    /// - `name` is empty or too long
+    /// - `value` is too long
+    /// - the attributes no longer fit in a block
+    pub fn set(&mut self, name: &str, value: &[u8]) -> Result<()> {
+        if name.is_empty() {
+            return Err(Error::InvalidName);
+        }
+        if name.len() > NAME_MAX {
+            return Err(Error::NameTooLong);
+        }
+        if value.len() > u16::MAX as usize {
+            return Err(Error::ValueTooLong);
+        }
+        let old_len = self
+            .get(name)
+            .map_or(0, |v| HEADER_SIZE + name.len() + v.len());
+        if self.records_len() - old_len + HEADER_SIZE + name.len() + value.len() > BLOCK_SIZE {
+            return Err(Error::OutOfSpace);
+        }
+        match self.entries.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_vec(),
+            None => self.entries.push((name.to_string(), value.to_vec())),
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over the attribute names, in insertion order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(n, _)| n.as_str())
+    }
+
+    /// Returns the number of bytes the records take.
+    /// The terminator is omitted when the records fill the whole block.
+    fn records_len(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(n, v)| HEADER_SIZE + n.len() + v.len())
+            .sum()
+    }
+
+    /// Decodes the attributes from a block.
+    ///
+    /// # Errors
+    /// Returns `Err` if:
+    /// - a record runs past the end of the block
+    /// - a name is not a valid UTF-8 string
+    pub fn from_block(block: &Block) -> Result<Self> {
+        let data = &block.data;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < BLOCK_SIZE && data[pos] != 0 {
+            let header = data.get(pos..(pos + HEADER_SIZE)).ok_or(Error::Corrupted)?;
+            let name_len = header[0] as usize;
+            let value_len = u16::from_le_bytes([header[1], header[2]]) as usize;
+            let name_start = pos + HEADER_SIZE;
+            let value_start = name_start + name_len;
+            let end = value_start + value_len;
+            if end > BLOCK_SIZE {
+                return Err(Error::Corrupted);
+            }
+            let name =
+                str::from_utf8(&data[name_start..value_start]).map_err(|_| Error::Corrupted)?;
+            entries.push((name.to_string(), data[value_start..end].to_vec()));
+            pos = end;
+        }
+        Ok(Self { entries })
+    }
+
+    /// Encodes the attributes into a block.
+    pub fn to_block(&self) -> Block {
+        let mut block = Block::default();
+        let mut pos = 0;
+        for (name, value) in &self.entries {
+            block.data[pos] = name.len() as u8;
+            block.data[(pos + 1)..(pos + HEADER_SIZE)]
+                .copy_from_slice(&(value.len() as u16).to_le_bytes());
+            pos += HEADER_SIZE;
+            block.data[pos..(pos + name.len())].copy_from_slice(name.as_bytes());
+            pos += name.len();
+            block.data[pos..(pos + value.len())].copy_from_slice(value);
+            pos += value.len();
+        }
+        block
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidName,
+    NameTooLong,
+    ValueTooLong,
+    OutOfSpace,
+    Corrupted,
+}
@@ -1,31 +1,56 @@
 use crate::{
-    hardware::storage::Storage,
+    hardware::storage::BlockDevice,
     kernel::{
-        file::OpenFileTable,
-        fs::{Filesystem, node::NodePtr},
+        clock::{Clock, SystemClock},
+        file::{AtimeMode, OpenFileTable},
+        fs::{Filesystem, node::NodePtr, transaction::Summary},
+        path_cache::{PATH_CACHE_CAPACITY, PathCache},
     },
 };
 
+pub mod clock;
 pub mod file;
 pub mod fs;
+pub mod hash;
+pub mod path_cache;
 pub mod syscall;
 
 /// A model for the kernel.
 pub struct Kernel {
-    storage: Storage,
+    storage: Box<dyn BlockDevice>,
     fs: Option<Filesystem>,
     open_files: OpenFileTable,
     curr_dir_ptr: NodePtr,
+    path_cache: PathCache,
+    clock: Box<dyn Clock>,
+    dry_run: bool,
+    dry_run_summary: Option<Summary>,
+    sparse_writes: bool,
+    safe_mode: bool,
+    max_file_size: Option<usize>,
+    reserve_std_fds: bool,
+    auto_reap: bool,
+    atime_mode: AtimeMode,
 }
 
 impl Kernel {
     /// Constructs a [Kernel].
-    pub fn new(storage: Storage) -> Self {
+    pub fn new(storage: impl BlockDevice + 'static) -> Self {
         Self {
-            storage,
+            storage: Box::new(storage),
             fs: None,
             open_files: OpenFileTable::new(),
             curr_dir_ptr: NodePtr::root(),
+            path_cache: PathCache::new(PATH_CACHE_CAPACITY),
+            clock: Box::new(SystemClock),
+            dry_run: false,
+            dry_run_summary: None,
+            sparse_writes: false,
+            safe_mode: false,
+            max_file_size: None,
+            reserve_std_fds: false,
+            auto_reap: true,
+            atime_mode: AtimeMode::default(),
         }
     }
 }
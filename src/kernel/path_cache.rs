@@ -0,0 +1,80 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::kernel::fs::{
+    node::NodePtr,
+    path::Path,
+    transaction::{self, Transaction},
+};
+
+/// How many resolved paths the cache holds.
+pub const PATH_CACHE_CAPACITY: usize = 64;
+
+/// A key made of the start directory node id and the path.
+type Key = (usize, String);
+
+/// A bounded cache of resolved paths.
+///
+/// The cache must be invalidated on any operation that may change the namespace.
+pub struct PathCache {
+    entries: BTreeMap<Key, NodePtr>,
+    order: VecDeque<Key>,
+    capacity: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl PathCache {
+    /// Constructs an empty [PathCache] holding up to `capacity` paths.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Resolves `path` relative to `start_node_ptr`, consulting the cache first.
+    pub fn resolve(
+        &mut self,
+        tx: &Transaction,
+        path: &Path,
+        start_node_ptr: NodePtr,
+    ) -> Result<NodePtr, transaction::Error> {
+        let key = (start_node_ptr.id(), path.as_str().to_string());
+        if let Some(&node_ptr) = self.entries.get(&key) {
+            self.hits += 1;
+            return Ok(node_ptr);
+        }
+        self.misses += 1;
+
+        let node_ptr = tx.path_node(path, start_node_ptr)?;
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity
+                && let Some(oldest) = self.order.pop_front()
+            {
+                self.entries.remove(&oldest);
+            }
+            self.entries.insert(key.clone(), node_ptr);
+            self.order.push_back(key);
+        }
+        Ok(node_ptr)
+    }
+
+    /// Drops every cached path.
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Returns the number of cache hits.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Returns the number of cache misses.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
@@ -1,12 +1,26 @@
-use crate::kernel::{
-    Kernel,
-    file::{FileDescription, FileDescriptor, FileStats},
-    fs::{
-        Filesystem,
-        directory::{self},
-        node::FileType,
-        path::Path,
-        transaction::{self, Transaction},
+use std::{borrow::Cow, collections::BTreeMap};
+
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+use crate::{
+    hardware::storage::{self, Storage, block::BLOCK_SIZE, image},
+    kernel::{
+        Kernel,
+        clock::{ManualClock, SystemClock},
+        file::{
+            AtimeMode, DirEntryInfo, ExtentMapping, FdStats, FileDescription, FileDescriptor,
+            FileStats, FsckReport, MkfsOptions, OpenOptions, RELATIME_INTERVAL, STD_FD_COUNT,
+            SortKey, SortOrder, TreeSummary,
+        },
+        fs::{
+            self, Filesystem,
+            alloc_map::{AllocFlag, Strategy},
+            directory::{self, DirEntryName},
+            node::{FileType, Node, NodePtr},
+            path::Path,
+            superblock::Superblock,
+            transaction::{self, CommitError, CommitRecord, ResolveStep, Summary, Transaction},
+        },
     },
 };
 
@@ -14,52 +28,176 @@ impl Kernel {
     /// Creates a file at `path`, if it doesn't exist.
     pub fn create(&mut self, path: &str) -> Result<()> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let mut tx = Transaction::new(fs, &mut self.storage);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
 
         let path = Path::new(path);
         let (parent, name) = path.split_last().ok_or(Error::NotPermitted)?;
-        let parent = tx.path_node(&parent, self.curr_dir_ptr)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
 
         tx.create_file(parent, &name, FileType::File)?;
-        tx.commit();
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Creates a file at `path` containing `data` in a single transaction.
+    /// The file never appears in its parent directory half-written.
+    pub fn create_with(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        if Self::fit_to_max_size(self.max_file_size, 0, data.len())? < data.len() {
+            return Err(Error::FileTooBig);
+        }
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let path = Path::new(path);
+        let (parent, name) = path.split_last().ok_or(Error::NotPermitted)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
+
+        tx.create_file_with(parent, &name, data)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Replaces the contents of the file at `path` with `data`,
+    /// so that readers see either the old or the new contents, never a partial file.
+    ///
+    /// The data is written to a temporary sibling first, which is then renamed over `path`.
+    /// Committed transactions are already on storage, so the data is durable before the rename.
+    /// The temporary file is named after the parent directory's node id and is created exclusively,
+    /// so an existing file of that name fails the write instead of being replaced.
+    pub fn safe_write(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let parent_id = self.dirname_node(path)?;
+        let target = Path::new(path);
+        let (parent, name) = target.split_last().ok_or(Error::NotPermitted)?;
+        // Nothing is written for a name the rename would reject
+        DirEntryName::try_from(name.as_ref())?;
+
+        let tmp_path = format!("{}.safe-write-{}", parent.as_str(), parent_id);
+        self.create_with(&tmp_path, data)?;
+        if let Err(e) = self.rename(&tmp_path, path) {
+            let _ = self.unlink(&tmp_path);
+            return Err(e);
+        }
         Ok(())
     }
 
     /// Opens the file at `path`, returning a corresponding file descriptor.
     pub fn open(&mut self, path: &str) -> Result<FileDescriptor> {
+        self.open_with(path, OpenOptions::default())
+    }
+
+    /// Opens the file at `path` with the given options.
+    ///
+    /// With `create` set, a missing regular file is created in the same transaction;
+    /// with `exclusive` set as well, an existing one fails with [transaction::Error::FileExists].
+    /// In dry-run mode, a file that would be created isn't, so it can't be opened either.
+    pub fn open_with(&mut self, path: &str, options: OpenOptions) -> Result<FileDescriptor> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let tx = Transaction::new(fs, &mut self.storage);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
 
         let path = Path::new(path);
-        let node_ptr = tx.path_node(&path, self.curr_dir_ptr)?;
-        tx.commit();
+        let node_ptr = match self.path_cache.resolve(&tx, &path, self.curr_dir_ptr) {
+            Err(transaction::Error::NodeNotFound) if options.create => {
+                let (parent, name) = path.split_last().ok_or(Error::NotPermitted)?;
+                let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
+                let node_ptr = tx.create_file(parent, &name, FileType::File)?;
+                if self.dry_run {
+                    self.dry_run_summary = Some(tx.abort());
+                    return Err(Error::NotPermitted);
+                }
+                self.path_cache.invalidate();
+                node_ptr
+            }
+            Ok(_) if options.create && options.exclusive => {
+                return Err(transaction::Error::FileExists.into());
+            }
+            result => result?,
+        };
+        tx.commit()?;
 
-        let fd = FileDescription::new(node_ptr);
+        let fd = FileDescription::new(node_ptr, options);
         Ok(self.open_file(fd))
     }
 
     /// Close the file descriptor referenced by `fd`.
+    /// In dry-run mode, the descriptor stays open, as its reservation is still held.
     pub fn close(&mut self, fd: FileDescriptor) -> Result<()> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
         let desc = self
             .open_files
-            .remove(&fd)
+            .get(&fd)
             .ok_or(Error::InvalidFileDescriptor)?;
+        let node_ptr = desc.node_ptr();
+        let mut reservation = desc.reservation;
         let is_opened = self
             .open_files
-            .values()
-            .any(|d| d.node_ptr() == desc.node_ptr());
-        if !is_opened {
-            let mut tx = Transaction::new(fs, &mut self.storage);
-            let node = tx.read_node(desc.node_ptr())?;
+            .iter()
+            .any(|(&other_fd, d)| other_fd != fd && d.node_ptr() == node_ptr);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        tx.release_reservation(&mut reservation)?;
+        if !is_opened && self.auto_reap {
+            let node = tx.read_node(node_ptr)?;
             if node.link_count == 0 {
-                tx.remove_node(desc.node_ptr())?;
+                tx.remove_node(node_ptr)?;
             };
-            tx.commit();
+        }
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        if !self.dry_run {
+            self.open_files.remove(&fd);
         }
         Ok(())
     }
 
+    /// Frees every file with no links that is neither open nor referenced by a directory entry,
+    /// such as those left behind by [Kernel::close] with auto-reaping off.
+    /// Returns how many nodes were freed.
+    pub fn reap(&mut self) -> Result<usize> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let reachable = tx.reachable_ids()?;
+        let dead: Vec<NodePtr> = tx
+            .list_nodes()?
+            .into_iter()
+            .filter(|(node_ptr, node)| {
+                // Directories are only removed through 'rmdir'
+                node.link_count == 0
+                    && node.filetype() != FileType::Dir
+                    && !reachable.contains(&node_ptr.id())
+                    && !self.open_files.values().any(|d| d.node_ptr() == *node_ptr)
+            })
+            .map(|(node_ptr, _)| node_ptr)
+            .collect();
+        for &node_ptr in &dead {
+            tx.remove_node(node_ptr)?;
+        }
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        Ok(dead.len())
+    }
+
+    /// Returns ids of zombie nodes: nodes that were unlinked but are kept alive by open files.
+    pub fn zombies(&mut self) -> Result<Vec<usize>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let mut zombies = Vec::new();
+        for desc in self.open_files.values() {
+            let node_ptr = desc.node_ptr();
+            if tx.read_node(node_ptr)?.link_count == 0 && !zombies.contains(&node_ptr.id()) {
+                zombies.push(node_ptr.id());
+            }
+        }
+        tx.commit()?;
+        zombies.sort();
+        Ok(zombies)
+    }
+
     /// Reposition the offset of the file descriptor referenced by `fd`.
     pub fn seek(&mut self, fd: FileDescriptor, offset: usize) -> Result<()> {
         let desc = self
@@ -73,60 +211,398 @@ impl Kernel {
     /// Reads up to `buf.len()` bytes into `buf` from the file referenced by `fd`.
     /// Returns the number of bytes read.
     pub fn read(&mut self, fd: FileDescriptor, buf: &mut [u8]) -> Result<usize> {
+        Ok(self.read_ex(fd, buf)?.0)
+    }
+
+    /// Reads like [Kernel::read], also returning whether the offset reached the end of the file,
+    /// which tells a short read at the end apart from a filled buffer.
+    /// An empty buffer reads nothing and never reports the end.
+    pub fn read_ex(&mut self, fd: FileDescriptor, buf: &mut [u8]) -> Result<(usize, bool)> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
         let desc = self
             .open_files
             .get_mut(&fd)
             .ok_or(Error::InvalidFileDescriptor)?;
-        let tx = Transaction::new(fs, &mut self.storage);
+        if buf.is_empty() {
+            return Ok((0, false));
+        }
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
         let bytes_read = tx.read_file_at(desc.node_ptr(), desc.offset, buf)?;
-        tx.commit();
+        let size = tx.read_node(desc.node_ptr())?.size;
+        if !self.dry_run {
+            Self::update_atime(&mut tx, desc.node_ptr(), self.atime_mode)?;
+        }
+        tx.commit()?;
         desc.offset += bytes_read;
-        Ok(bytes_read)
+        desc.bytes_read += bytes_read;
+        Ok((bytes_read, desc.offset >= size))
     }
 
     /// Writes up to `buf.len()` bytes from `buf` to the file referenced by `fd`.
-    /// Returns the number of bytes written.
+    /// Returns the number of bytes written, which is short if the filesystem fills up midway
+    /// or the write crosses the maximum file size.
     pub fn write(&mut self, fd: FileDescriptor, buf: &[u8]) -> Result<usize> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
         let desc = self
             .open_files
             .get_mut(&fd)
             .ok_or(Error::InvalidFileDescriptor)?;
-        let mut tx = Transaction::new(fs, &mut self.storage);
-        let bytes_written = tx.write_file_at(desc.node_ptr(), desc.offset, buf)?;
-        tx.commit();
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let buf = &buf[..Self::fit_to_max_size(self.max_file_size, desc.offset, buf.len())?];
+        // A failed write rolls back the allocations, including a refilled reservation
+        let reservation = desc.reservation;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        tx.set_sparse(self.sparse_writes);
+        let result = Self::write_desc(&mut tx, desc, buf, self.safe_mode).and_then(|written| {
+            self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+            Ok(written)
+        });
+        let (bytes_written, is_dir) = match result {
+            Ok(written) => written,
+            Err(e) => {
+                desc.reservation = reservation;
+                return Err(e);
+            }
+        };
+        // An aborted write leaves the descriptor as it was
+        if self.dry_run {
+            desc.reservation = reservation;
+            return Ok(bytes_written);
+        }
+        if is_dir {
+            self.path_cache.invalidate();
+        }
         desc.offset += bytes_written;
+        desc.bytes_written += bytes_written;
         Ok(bytes_written)
     }
 
+    /// Copies up to `len` bytes from the file referenced by `src_fd` at `src_offset`
+    /// to the file referenced by `dst_fd` at `dst_offset`, without a round trip through a buffer.
+    /// Holes in the source are kept where whole blocks line up.
+    /// Both descriptors' offsets are moved just past the copied bytes.
+    /// Returns the number of bytes copied, which is short at the end of the source.
+    ///
+    /// Overlapping ranges within one file fail with [Error::NotPermitted].
+    pub fn copy_file_range(
+        &mut self,
+        src_fd: FileDescriptor,
+        src_offset: usize,
+        dst_fd: FileDescriptor,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<usize> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let src_ptr = self
+            .open_files
+            .get(&src_fd)
+            .ok_or(Error::InvalidFileDescriptor)?
+            .node_ptr();
+        let dst_ptr = self
+            .open_files
+            .get(&dst_fd)
+            .ok_or(Error::InvalidFileDescriptor)?
+            .node_ptr();
+        if src_ptr == dst_ptr && src_offset < dst_offset + len && dst_offset < src_offset + len {
+            return Err(Error::NotPermitted);
+        }
+        let len = Self::fit_to_max_size(self.max_file_size, dst_offset, len)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        tx.set_sparse(self.sparse_writes);
+        let copied = tx.copy_file_range(src_ptr, src_offset, dst_ptr, dst_offset, len)?;
+        // Raw writes to a directory may change the namespace
+        let is_dir = tx.read_node(dst_ptr)?.filetype() == FileType::Dir;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        if self.dry_run {
+            return Ok(copied);
+        }
+        if is_dir {
+            self.path_cache.invalidate();
+        }
+
+        if let Some(desc) = self.open_files.get_mut(&src_fd) {
+            desc.offset = src_offset + copied;
+            desc.bytes_read += copied;
+        }
+        if let Some(desc) = self.open_files.get_mut(&dst_fd) {
+            desc.offset = dst_offset + copied;
+            desc.bytes_written += copied;
+        }
+        Ok(copied)
+    }
+
+    /// Returns up to `len` bytes of the file referenced by `fd` at its offset,
+    /// borrowing them from the storage instead of copying them where it can.
+    /// The offset is not advanced.
+    ///
+    /// Like [Transaction::peek], this only works within a single block:
+    /// the offset must be block-aligned and `len` at most one block.
+    pub fn peek(&mut self, fd: FileDescriptor, len: usize) -> Result<Cow<'_, [u8]>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let desc = self
+            .open_files
+            .get(&fd)
+            .ok_or(Error::InvalidFileDescriptor)?;
+        let offset = desc.offset;
+        if !offset.is_multiple_of(BLOCK_SIZE) || len > BLOCK_SIZE {
+            return Err(transaction::Error::Unaligned.into());
+        }
+        let tx = Transaction::new(fs, self.storage.as_mut());
+        let node = tx.read_node(desc.node_ptr())?;
+        tx.commit()?;
+
+        // Nothing is buffered once the transaction is committed, so the storage holds the data
+        let len = len.min(node.size.saturating_sub(offset));
+        if node.is_inline() {
+            let data = node
+                .inline_data()
+                .get(offset..offset + len)
+                .unwrap_or_default();
+            return Ok(Cow::Owned(data.to_vec()));
+        }
+        match node.get_block_id_from_offset(offset) {
+            Some(block_id) => Ok(match self.storage.read_block_ref(block_id)? {
+                Cow::Borrowed(block) => Cow::Borrowed(&block.data[..len]),
+                Cow::Owned(block) => Cow::Owned(block.data[..len].to_vec()),
+            }),
+            None => Ok(Cow::Owned(vec![0u8; len])),
+        }
+    }
+
+    /// Reads a fixed-size record of type `T` from the file referenced by `fd` at its offset,
+    /// advancing the offset past it.
+    ///
+    /// # Errors
+    /// Returns [Error::UnexpectedEof] if the file ends before the whole record,
+    /// leaving the offset unchanged.
+    pub fn read_struct<T: FromBytes>(&mut self, fd: FileDescriptor) -> Result<T> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let desc = self
+            .open_files
+            .get_mut(&fd)
+            .ok_or(Error::InvalidFileDescriptor)?;
+        let mut buf = vec![0u8; size_of::<T>()];
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        let bytes_read = tx.read_file_at(desc.node_ptr(), desc.offset, &mut buf)?;
+        if bytes_read < buf.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        if !self.dry_run {
+            Self::update_atime(&mut tx, desc.node_ptr(), self.atime_mode)?;
+        }
+        tx.commit()?;
+        desc.offset += bytes_read;
+        desc.bytes_read += bytes_read;
+        Ok(T::read_from_bytes(&buf).expect("'buf' must be the size of 'T'"))
+    }
+
+    /// Writes `value` as a fixed-size record to the file referenced by `fd` at its offset,
+    /// advancing the offset past it.
+    /// Unlike [Kernel::write], the record is never written partially.
+    pub fn write_struct<T: IntoBytes + Immutable>(
+        &mut self,
+        fd: FileDescriptor,
+        value: &T,
+    ) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let desc = self
+            .open_files
+            .get_mut(&fd)
+            .ok_or(Error::InvalidFileDescriptor)?;
+        let bytes = value.as_bytes();
+        if Self::fit_to_max_size(self.max_file_size, desc.offset, bytes.len())? < bytes.len() {
+            return Err(Error::FileTooBig);
+        }
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        tx.set_sparse(self.sparse_writes);
+        tx.write_file_all(desc.node_ptr(), desc.offset, bytes)?;
+        // Raw writes to a directory may change the namespace
+        let is_dir = tx.read_node(desc.node_ptr())?.filetype() == FileType::Dir;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        if self.dry_run {
+            return Ok(());
+        }
+        if is_dir {
+            self.path_cache.invalidate();
+        }
+        desc.offset += bytes.len();
+        desc.bytes_written += bytes.len();
+        Ok(())
+    }
+
+    /// Returns how many of `len` bytes written at `offset` stay within `max_file_size`.
+    ///
+    /// # Errors
+    /// Returns [Error::FileTooBig] if not even one byte fits.
+    fn fit_to_max_size(max_file_size: Option<usize>, offset: usize, len: usize) -> Result<usize> {
+        match max_file_size {
+            Some(max) if len > 0 && offset >= max => Err(Error::FileTooBig),
+            Some(max) => Ok(len.min(max - offset)),
+            None => Ok(len),
+        }
+    }
+
+    /// Stamps the access time of the node after a read, if `mode` asks for it.
+    fn update_atime(tx: &mut Transaction, node_ptr: NodePtr, mode: AtimeMode) -> Result<()> {
+        let node = tx.read_node(node_ptr)?;
+        let now = tx.now();
+        let update = match mode {
+            AtimeMode::Strict => true,
+            AtimeMode::Relative => {
+                node.atime() <= node.mtime() || now >= node.atime() + RELATIME_INTERVAL
+            }
+            AtimeMode::Never => false,
+        };
+        if update {
+            tx.set_times(node_ptr, now, node.mtime())?;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` to the file of `desc` at its offset.
+    /// Returns the number of bytes written and whether the file is a directory.
+    ///
+    /// In safe mode, a write that would run out of extents fails as a whole instead of ending short.
+    fn write_desc(
+        tx: &mut Transaction,
+        desc: &mut FileDescription,
+        buf: &[u8],
+        safe_mode: bool,
+    ) -> Result<(usize, bool)> {
+        let node_ptr = desc.node_ptr();
+        let node = tx.read_node(node_ptr)?;
+        let blocks = (desc.offset / BLOCK_SIZE)..(desc.offset + buf.len()).div_ceil(BLOCK_SIZE);
+        if safe_mode && !Transaction::can_map(&node, blocks) {
+            return Err(Error::FileTooFragmented);
+        }
+        let bytes_written =
+            tx.write_file_at_reserved(node_ptr, desc.offset, buf, Some(&mut desc.reservation))?;
+        let node = tx.read_node(node_ptr)?;
+        // Free space may be too fragmented for the blocks to merge into the remaining extents
+        if safe_mode && bytes_written < buf.len() && node.get_extents().iter().all(|e| !e.is_null())
+        {
+            return Err(Error::FileTooFragmented);
+        }
+        // Raw writes to a directory may change the namespace
+        Ok((bytes_written, node.filetype() == FileType::Dir))
+    }
+
+    /// Returns the I/O counters of the file descriptor referenced by `fd`.
+    /// Short reads and writes count the bytes actually transferred.
+    pub fn fd_stats(&self, fd: FileDescriptor) -> Result<FdStats> {
+        let desc = self
+            .open_files
+            .get(&fd)
+            .ok_or(Error::InvalidFileDescriptor)?;
+        Ok(FdStats {
+            node_id: desc.node_ptr().id(),
+            offset: desc.offset,
+            bytes_read: desc.bytes_read,
+            bytes_written: desc.bytes_written,
+        })
+    }
+
+    /// Maps the file referenced by `fd` into memory, returning its whole contents.
+    ///
+    /// The mapping is copy-based: changes to the buffer are not seen by the file
+    /// until they are written back with [Kernel::unmap_file].
+    pub fn map_file(&mut self, fd: FileDescriptor) -> Result<Vec<u8>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let desc = self
+            .open_files
+            .get(&fd)
+            .ok_or(Error::InvalidFileDescriptor)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+        let node = tx.read_node(desc.node_ptr())?;
+        let mut data = vec![0u8; node.size];
+        tx.read_file_at(desc.node_ptr(), 0, &mut data)?;
+        tx.commit()?;
+        Ok(data)
+    }
+
+    /// Writes a buffer obtained from [Kernel::map_file] back to the file referenced by `fd`.
+    /// The file grows if `data` is larger than it, but is never shrunk.
+    pub fn unmap_file(&mut self, fd: FileDescriptor, data: &[u8]) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let desc = self
+            .open_files
+            .get(&fd)
+            .ok_or(Error::InvalidFileDescriptor)?;
+        if Self::fit_to_max_size(self.max_file_size, 0, data.len())? < data.len() {
+            return Err(Error::FileTooBig);
+        }
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        tx.set_sparse(self.sparse_writes);
+        tx.write_file_all(desc.node_ptr(), 0, data)?;
+        // Raw writes to a directory may change the namespace
+        let is_dir = tx.read_node(desc.node_ptr())?.filetype() == FileType::Dir;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        if is_dir {
+            self.path_cache.invalidate();
+        }
+        Ok(())
+    }
+
     /// Creates a hard link at `new_path` to the file at `old_path`.
     pub fn link(&mut self, old_path: &str, new_path: &str) -> Result<()> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let mut tx = Transaction::new(fs, &mut self.storage);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
 
         let old_path = Path::new(old_path);
-        let node_ptr = tx.path_node(&old_path, self.curr_dir_ptr)?;
+        let node_ptr = self.path_cache.resolve(&tx, &old_path, self.curr_dir_ptr)?;
 
         let new_path = Path::new(new_path);
         let (parent, name) = new_path.split_last().ok_or(Error::NotPermitted)?;
-        let parent = tx.path_node(&parent, self.curr_dir_ptr)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
 
         tx.link_file(parent, node_ptr, &name)?;
-        tx.commit();
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
         Ok(())
     }
 
+    /// Creates a directory at `dst_path` filled with hard links to the files of the directory at `src_path`.
+    /// If `recursive` is true, subdirectories are recreated and linked as well.
+    /// Returns the number of links created.
+    pub fn link_tree(&mut self, src_path: &str, dst_path: &str, recursive: bool) -> Result<usize> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let src_path = Path::new(src_path);
+        let src_ptr = self.path_cache.resolve(&tx, &src_path, self.curr_dir_ptr)?;
+
+        let dst_path = Path::new(dst_path);
+        let (parent, name) = dst_path.split_last().ok_or(Error::NotPermitted)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
+        let dst_ptr = tx.create_directory(parent, &name)?;
+
+        let count = tx.link_tree(src_ptr, dst_ptr, recursive)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
+        Ok(count)
+    }
+
     /// Removes the hard link at `path` from the filesystem.
     /// If it was the last hard link to the file, it is deleted.
     /// If the file is currently opened, it is deleted after it's closed.
     pub fn unlink(&mut self, path: &str) -> Result<()> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let mut tx = Transaction::new(fs, &mut self.storage);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
 
         let path = Path::new(path);
         let (parent, name) = path.split_last().ok_or(Error::NotPermitted)?;
-        let parent = tx.path_node(&parent, self.curr_dir_ptr)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
         let node_ptr = tx.find_entry(parent, &name)?.node_ptr();
 
         let is_opened = self
@@ -135,138 +611,999 @@ impl Kernel {
             .any(|desc| desc.node_ptr() == node_ptr);
 
         tx.unlink_file(parent, &name, !is_opened)?;
-        tx.commit();
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Moves the file at `old_path` to `new_path`, replacing a non-directory file there.
+    /// On failure, no changes are made.
+    ///
+    /// Each path is resolved on its own: an absolute one from the root,
+    /// a relative one from the current directory.
+    pub fn rename(&mut self, old_path: &str, new_path: &str) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let old_path = Path::new(old_path);
+        let (src_parent, src_name) = old_path.split_last().ok_or(Error::NotPermitted)?;
+        let src_parent = self
+            .path_cache
+            .resolve(&tx, &src_parent, self.curr_dir_ptr)?;
+        let new_path = Path::new(new_path);
+        let (dst_parent, dst_name) = new_path.split_last().ok_or(Error::NotPermitted)?;
+        let dst_parent = self
+            .path_cache
+            .resolve(&tx, &dst_parent, self.curr_dir_ptr)?;
+
+        let is_opened = tx.find_entry(dst_parent, &dst_name).is_ok_and(|e| {
+            self.open_files
+                .values()
+                .any(|d| d.node_ptr() == e.node_ptr())
+        });
+
+        if let Err(e) = tx.rename(src_parent, &src_name, dst_parent, &dst_name, !is_opened) {
+            // Restores the allocation maps
+            tx.abort();
+            return Err(e.into());
+        }
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
         Ok(())
     }
 
     /// Creates a symbolic link to `target` at `path`.
     pub fn symlink(&mut self, target: &str, path: &str) -> Result<()> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let mut tx = Transaction::new(fs, &mut self.storage);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
 
         let path = Path::new(path);
         let (parent, name) = path.split_last().ok_or(Error::NotPermitted)?;
-        let parent = tx.path_node(&parent, self.curr_dir_ptr)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
 
         let target = Path::new(target);
         tx.create_symlink(parent, &name, &target)?;
-        tx.commit();
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Sets the access and modification times of the file at `path`, in seconds since the Unix epoch,
+    /// for example to restore them from a backup. Works on directories too.
+    pub fn utimes(&mut self, path: &str, atime: u64, mtime: u64) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+
+        tx.set_times(node_ptr, atime, mtime)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
         Ok(())
     }
 
     /// Truncates the file at `path` to be truncated to a size of `size` bytes.
     pub fn truncate(&mut self, path: &str, size: usize) -> Result<()> {
+        if self.max_file_size.is_some_and(|max| size > max) {
+            return Err(Error::FileTooBig);
+        }
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let mut tx = Transaction::new(fs, &mut self.storage);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
 
         let path = Path::new(path);
-        let node_ptr = tx.path_node(&path, self.curr_dir_ptr)?;
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
 
         tx.truncate_file(node_ptr, size)?;
-        tx.commit();
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        Ok(())
+    }
+
+    /// Truncates the file referenced by `fd` to a size of `size` bytes.
+    ///
+    /// The offset of the descriptor is left unchanged, even if it now lies past the end of the file,
+    /// so reads from it return 0 bytes until it is repositioned with [Kernel::seek].
+    pub fn ftruncate(&mut self, fd: FileDescriptor, size: usize) -> Result<()> {
+        if self.max_file_size.is_some_and(|max| size > max) {
+            return Err(Error::FileTooBig);
+        }
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let desc = self
+            .open_files
+            .get(&fd)
+            .ok_or(Error::InvalidFileDescriptor)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        tx.truncate_file(desc.node_ptr(), size)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        Ok(())
+    }
+
+    /// Deallocates the `[offset, offset + len)` range of the file at `path`, leaving a hole.
+    /// The size of the file is left unchanged and the range reads as zeroes.
+    pub fn punch_hole(&mut self, path: &str, offset: usize, len: usize) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+
+        tx.punch_hole(node_ptr, offset, len)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
         Ok(())
     }
 
+    /// Sets the extended attribute `name` of the file at `path` to `value`.
+    pub fn setxattr(&mut self, path: &str, name: &str, value: &[u8]) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        tx.set_xattr(node_ptr, name, value)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        Ok(())
+    }
+
+    /// Returns the value of the extended attribute `name` of the file at `path`, if it's set.
+    pub fn getxattr(&mut self, path: &str, name: &str) -> Result<Option<Vec<u8>>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        let xattrs = tx.read_xattrs(node_ptr)?;
+        tx.commit()?;
+        Ok(xattrs.get(name).map(<[u8]>::to_vec))
+    }
+
+    /// Returns the names of the extended attributes of the file at `path`.
+    pub fn listxattr(&mut self, path: &str) -> Result<Vec<String>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        let xattrs = tx.read_xattrs(node_ptr)?;
+        tx.commit()?;
+        Ok(xattrs.names().map(String::from).collect())
+    }
+
+    /// Returns the SHA-256 digest of the contents of the file at `path`.
+    pub fn hash_file(&mut self, path: &str) -> Result<[u8; 32]> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        let digest = tx.hash_file(node_ptr)?;
+        tx.commit()?;
+        Ok(digest)
+    }
+
+    /// Maps the bytes of the file at `path` in `[offset, offset + len)` to where they are stored,
+    /// like Linux's `FIEMAP`. The range is cut off at the end of the file.
+    pub fn fiemap(&mut self, path: &str, offset: usize, len: usize) -> Result<Vec<ExtentMapping>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        let node = tx.read_node(node_ptr)?;
+        tx.commit()?;
+        Ok(ExtentMapping::map_node(&node, offset, len))
+    }
+
+    /// Checks whether the SHA-256 digest of the file at `path` is `expected`.
+    pub fn verify_file(&mut self, path: &str, expected: &[u8; 32]) -> Result<bool> {
+        Ok(self.hash_file(path)? == *expected)
+    }
+
+    /// Lists every path under the directory `path` depth-first, in pre-order,
+    /// with its file type and size. Paths are relative to `path`; symlinks are not followed.
+    pub fn walk(&mut self, path: &str) -> Result<Vec<(String, FileType, usize)>> {
+        Ok(self
+            .walk_nodes(path)?
+            .into_iter()
+            .map(|(path, node)| (path, node.filetype(), node.size))
+            .collect())
+    }
+
+    /// Lists every path under the directory `path`, like [Kernel::walk],
+    /// whose node was modified at or after `since`, in seconds since the Unix epoch.
+    pub fn find_modified_since(&mut self, path: &str, since: u64) -> Result<Vec<String>> {
+        Ok(self
+            .walk_nodes(path)?
+            .into_iter()
+            .filter(|(_, node)| node.mtime() >= since)
+            .map(|(path, _)| path)
+            .collect())
+    }
+
+    // Internal implementation of 'walk', pairing every path with its node.
+    fn walk_nodes(&mut self, path: &str) -> Result<Vec<(String, Node)>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        if tx.read_node(node_ptr)?.filetype() != FileType::Dir {
+            return Err(Error::NotDir);
+        }
+        let mut entries = Vec::new();
+        tx.walk(node_ptr, |path, entry| {
+            entries.push((path.to_string(), entry.node_ptr().id()));
+            Ok(())
+        })?;
+        let ids: Vec<usize> = entries.iter().map(|(_, id)| *id).collect();
+        let nodes = tx.read_nodes(&ids)?;
+        tx.commit()?;
+        Ok(entries
+            .into_iter()
+            .zip(nodes)
+            .map(|((path, _), node)| (path, node))
+            .collect())
+    }
+
+    /// Returns the absolute path of every hard link to the node at `path`.
+    pub fn find_links(&mut self, path: &str) -> Result<Vec<String>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        let links = tx.find_links(node_ptr)?;
+        tx.commit()?;
+        Ok(links)
+    }
+
+    /// Checks whether `path` resolves to a file, following symlinks.
+    /// Any failure to resolve it, such as a missing intermediate directory, counts as `false`.
+    pub fn exists(&mut self, path: &str) -> bool {
+        self.stat(path).is_ok()
+    }
+
+    /// Checks whether `path` resolves to a directory, following symlinks.
+    pub fn is_dir(&mut self, path: &str) -> Result<bool> {
+        Ok(self.stat(path)?.filetype == FileType::Dir)
+    }
+
     /// Returns statistics about a file `path`.
     pub fn stat(&mut self, path: &str) -> Result<FileStats> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let tx = Transaction::new(fs, &mut self.storage);
+        let tx = Transaction::new(fs, self.storage.as_mut());
 
         let path = Path::new(path);
-        let node_ptr = tx.path_node(&path, self.curr_dir_ptr)?;
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
         let node = tx.read_node(node_ptr)?;
-        tx.commit();
+        tx.commit()?;
+        Ok(FileStats::new(node_ptr, node))
+    }
+
+    /// Resolves the directory containing the final component of `path`, returning its node id.
+    /// The final component itself isn't resolved, so it doesn't have to exist.
+    pub fn dirname_node(&mut self, path: &str) -> Result<usize> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let (parent, _) = path.split_last().ok_or(Error::NotPermitted)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
+        if tx.read_node(parent)?.filetype() != FileType::Dir {
+            return Err(Error::NotDir);
+        }
+        tx.commit()?;
+        Ok(parent.id())
+    }
+
+    /// Resolves `path`, returning a [ResolveStep] for each component visited.
+    /// Bypasses the path cache so that every step is observed.
+    pub fn resolve_trace(&mut self, path: &str) -> Result<Vec<ResolveStep>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let trace = tx.resolve_trace(&path, self.curr_dir_ptr)?;
+        tx.commit()?;
+        Ok(trace)
+    }
+
+    /// Returns statistics about a file `path`.
+    /// Unlike [Kernel::stat], doesn't follow `path` if it's a symlink.
+    pub fn lstat(&mut self, path: &str) -> Result<FileStats> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let node_ptr = tx.path_node_nofollow(&path, self.curr_dir_ptr)?;
+        let node = tx.read_node(node_ptr)?;
+        tx.commit()?;
         Ok(FileStats::new(node_ptr, node))
     }
 
     /// Creates a directory at `path`.
     pub fn mkdir(&mut self, path: &str) -> Result<()> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let mut tx = Transaction::new(fs, &mut self.storage);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
 
         let path = Path::new(path);
         let (parent, name) = path.split_last().ok_or(Error::NotPermitted)?;
-        let parent = tx.path_node(&parent, self.curr_dir_ptr)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
 
         tx.create_directory(parent, &name)?;
-        tx.commit();
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
         Ok(())
     }
 
     /// Deletes the directory at `path`.
     pub fn rmdir(&mut self, path: &str) -> Result<()> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let mut tx = Transaction::new(fs, &mut self.storage);
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
 
         let path = Path::new(path);
         let (parent, name) = path.split_last().ok_or(Error::NotPermitted)?;
         if name == "." || name == ".." {
             return Err(Error::NotPermitted);
         }
-        let parent = tx.path_node(&parent, self.curr_dir_ptr)?;
+        let parent = self.path_cache.resolve(&tx, &parent, self.curr_dir_ptr)?;
 
         tx.remove_directory(parent, &name)?;
-        tx.commit();
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Moves every entry of the directory at `src` into the directory at `dst`,
+    /// then removes `src`. The merge happens in one transaction, so it either fully happens or not at all.
+    ///
+    /// Name collisions fail the merge, unless `overwrite` is set,
+    /// in which case files in `dst` are replaced. Directories are never replaced.
+    pub fn merge_dirs(&mut self, src: &str, dst: &str, overwrite: bool) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let src = Path::new(src);
+        let (src_parent, src_name) = src.split_last().ok_or(Error::NotPermitted)?;
+        if src_name == "." || src_name == ".." {
+            return Err(Error::NotPermitted);
+        }
+        let src_parent = self
+            .path_cache
+            .resolve(&tx, &src_parent, self.curr_dir_ptr)?;
+        let dst = Path::new(dst);
+        let dst = self.path_cache.resolve(&tx, &dst, self.curr_dir_ptr)?;
+
+        let opened: Vec<NodePtr> = self.open_files.values().map(|d| d.node_ptr()).collect();
+        tx.merge_directories(src_parent, &src_name, dst, overwrite, &opened)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Relocates nodes to the lowest free ids, rewriting every directory entry that references them.
+    /// Open files and the current directory follow their nodes. Returns how many nodes moved.
+    pub fn compact_nodes(&mut self) -> Result<usize> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let moves = tx.compact_nodes()?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        if self.dry_run {
+            return Ok(moves.len());
+        }
+        let new_ids: BTreeMap<usize, usize> = moves.iter().copied().collect();
+        let remap = |node_ptr: NodePtr| match new_ids.get(&node_ptr.id()) {
+            Some(&new) => NodePtr::new(new),
+            None => node_ptr,
+        };
+        for desc in self.open_files.values_mut() {
+            desc.set_node_ptr(remap(desc.node_ptr()));
+        }
+        self.curr_dir_ptr = remap(self.curr_dir_ptr);
+        self.path_cache.invalidate();
+        Ok(moves.len())
+    }
+
+    /// Compacts the directory at `path`, reclaiming space left by removed entries.
+    pub fn compact(&mut self, path: &str) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+
+        tx.compact_directory(node_ptr)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
         Ok(())
     }
 
     /// Changes the current directory.
     pub fn cd(&mut self, path: &str) -> Result<()> {
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let tx = Transaction::new(fs, &mut self.storage);
+        let tx = Transaction::new(fs, self.storage.as_mut());
 
         let path = Path::new(path);
-        let node_ptr = tx.path_node(&path, self.curr_dir_ptr)?;
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
         let node = tx.read_node(node_ptr)?;
         if node.filetype() != FileType::Dir {
             return Err(Error::NotDir);
         }
-        tx.commit();
+        tx.commit()?;
 
         self.curr_dir_ptr = node_ptr;
         Ok(())
     }
 
-    /// Returns the list of hard links inside the directory at `path`.
-    pub fn ls(&mut self, path: &str) -> Result<Vec<(String, usize)>> {
+    /// Returns the list of hard links inside the directory at `path`, failing if it's not a directory.
+    /// Entries whose names start with `.` (including `.` and `..`) are only listed if `show_hidden` is true.
+    ///
+    /// Entries are sorted by `order` after they are collected, which doesn't change their order on disk.
+    /// Sorting by size has to read the node of every entry.
+    pub fn ls(
+        &mut self,
+        path: &str,
+        show_hidden: bool,
+        order: SortOrder,
+    ) -> Result<Vec<(String, usize)>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+
+        let path = Path::new(path);
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        let node = tx.read_node(node_ptr)?;
+        if node.filetype() != FileType::Dir {
+            return Err(Error::NotDir);
+        }
+        let dir = tx.read_directory(node_ptr)?;
+
+        let mut list = Vec::new();
+        for entry in dir.as_slice().iter().filter(|e| !e.is_null()) {
+            let name = entry.name().map_err(transaction::Error::from)?;
+            if !show_hidden && name.starts_with('.') {
+                continue;
+            }
+            let key = match order.key {
+                SortKey::Name => 0,
+                SortKey::Size => tx.read_node(entry.node_ptr())?.size,
+                SortKey::Node => entry.node_ptr().id(),
+            };
+            list.push((key, name.to_string(), entry.node_ptr().id()));
+        }
+        tx.commit()?;
+
+        list.sort_by(|a, b| {
+            let ordering = match order.key {
+                SortKey::Name => a.1.cmp(&b.1),
+                SortKey::Size | SortKey::Node => a.0.cmp(&b.0),
+            };
+            let ordering = if order.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            ordering.then_with(|| a.1.cmp(&b.1))
+        });
+        Ok(list
+            .into_iter()
+            .map(|(_, name, node_id)| (name, node_id))
+            .collect())
+    }
+
+    /// Returns the list of hard links inside the directory at `path`, along with the statistics of their files.
+    /// Hidden entries are filtered and sorted the same way as in [Kernel::ls].
+    pub fn ls_long(
+        &mut self,
+        path: &str,
+        show_hidden: bool,
+        order: SortOrder,
+    ) -> Result<Vec<(String, FileStats)>> {
+        let list = self.ls(path, show_hidden, order)?;
+
         let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
-        let tx = Transaction::new(fs, &mut self.storage);
+        let tx = Transaction::new(fs, self.storage.as_mut());
+        let ids: Vec<usize> = list.iter().map(|(_, id)| *id).collect();
+        let nodes = tx.read_nodes(&ids)?;
+        tx.commit()?;
+        Ok(list
+            .into_iter()
+            .zip(nodes)
+            .map(|((name, id), node)| (name, FileStats::new(NodePtr::new(id), node)))
+            .collect())
+    }
+
+    /// Lists every entry of the directory at `path` in directory order, including `.` and `..`,
+    /// together with the size and link count of its node. Nodes are read in a single batch.
+    pub fn readdir_full(&mut self, path: &str) -> Result<Vec<DirEntryInfo>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
 
         let path = Path::new(path);
-        let node_ptr = tx.path_node(&path, self.curr_dir_ptr)?;
+        let node_ptr = self.path_cache.resolve(&tx, &path, self.curr_dir_ptr)?;
+        if tx.read_node(node_ptr)?.filetype() != FileType::Dir {
+            return Err(Error::NotDir);
+        }
         let dir = tx.read_directory(node_ptr)?;
-        tx.commit();
+        let entries: Vec<_> = dir.as_slice().iter().filter(|e| !e.is_null()).collect();
+        let ids: Vec<usize> = entries.iter().map(|e| e.node_ptr().id()).collect();
+        let nodes = tx.read_nodes(&ids)?;
+        let mut list = Vec::with_capacity(entries.len());
+        for (entry, node) in entries.into_iter().zip(nodes) {
+            list.push(DirEntryInfo {
+                name: entry.name().map_err(transaction::Error::from)?.to_string(),
+                node_id: entry.node_ptr().id(),
+                filetype: entry.filetype(),
+                size: node.size,
+                link_count: node.link_count,
+            });
+        }
+        tx.commit()?;
+        Ok(list)
+    }
+
+    /// Returns statistics about every allocated node, keyed by node id.
+    pub fn list_nodes(&mut self) -> Result<Vec<(usize, FileStats)>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+        let nodes = tx.list_nodes()?;
+        tx.commit()?;
 
-        dir.as_slice()
+        Ok(nodes
+            .into_iter()
+            .map(|(node_ptr, node)| (node_ptr.id(), FileStats::new(node_ptr, node)))
+            .collect())
+    }
+
+    /// Returns the id of the node that references the block at `block_id`.
+    /// Returns `None` for free and metadata blocks.
+    pub fn block_owner(&mut self, block_id: usize) -> Result<Option<usize>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+        let owner = tx.block_owner(block_id)?;
+        tx.commit()?;
+        Ok(owner.map(|node_ptr| node_ptr.id()))
+    }
+
+    /// Returns the block map as (flag, start, len) runs of free and used blocks.
+    pub fn freemap(&self) -> Result<Vec<(AllocFlag, usize, usize)>> {
+        let fs = self.fs.as_ref().ok_or(Error::FilesystemNotMounted)?;
+        Ok(fs.block_map().runs())
+    }
+
+    /// Returns the extents of the node at `node_id` as (start, end) tuples.
+    /// A hole is described by a zero start and its length as the end.
+    #[cfg(feature = "debug-extents")]
+    pub fn get_extents(&mut self, node_id: usize) -> Result<Vec<(usize, usize)>> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+        let node = tx.read_node(NodePtr::new(node_id))?;
+        tx.commit()?;
+        Ok(node
+            .get_extents()
             .iter()
-            .filter(|e| !e.is_null())
-            .map(|e| {
-                let name = e.name().map_err(transaction::Error::from)?.to_string();
-                Ok((name, e.node_ptr().id()))
-            })
-            .collect()
+            .take_while(|e| !e.is_null())
+            .map(|e| (e.start(), e.end()))
+            .collect())
+    }
+
+    /// Replaces the extents of the node at `node_id` with (start, end) tuples.
+    /// Referenced blocks that are free get allocated.
+    #[cfg(feature = "debug-extents")]
+    pub fn set_extents(&mut self, node_id: usize, extents: &[(usize, usize)]) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        let extents: Vec<_> = extents
+            .iter()
+            .map(|&(start, end)| crate::kernel::fs::node::Extent::new(start, end))
+            .collect();
+        tx.set_extents(NodePtr::new(node_id), &extents)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        Ok(())
+    }
+
+    /// Checks the filesystem for orphaned nodes, skipping those kept alive by open files.
+    /// If `repair` is true, orphaned regular files are linked into `/lost+found`.
+    pub fn fsck(&mut self, repair: bool) -> Result<FsckReport> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+
+        let orphans: Vec<NodePtr> = tx
+            .find_orphans()?
+            .into_iter()
+            .filter(|&p| !self.open_files.values().any(|d| d.node_ptr() == p))
+            .collect();
+        let recovered = if repair {
+            tx.recover_orphans(&orphans)?
+        } else {
+            Vec::new()
+        };
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        if !recovered.is_empty() {
+            self.path_cache.invalidate();
+        }
+
+        Ok(FsckReport {
+            orphans: orphans.iter().map(|p| p.id()).collect(),
+            recovered: recovered.iter().map(|p| p.id()).collect(),
+        })
+    }
+
+    /// Counts nodes as a quick leak check.
+    /// Returns a (reachable, allocated) tuple: the number of nodes reachable from the root
+    /// and the number marked used in the node map, without the null node.
+    /// The counts differ when there are orphans or zombies.
+    pub fn reachable_nodes(&mut self) -> Result<(usize, usize)> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let allocated = fs.node_map().used_ids().filter(|&id| id != 0).count();
+        let tx = Transaction::new(fs, self.storage.as_mut());
+        let reachable = tx.reachable_ids()?.len();
+        tx.commit()?;
+        Ok((reachable, allocated))
+    }
+
+    /// Counts the nodes reachable from the root by file type, along with the bytes and blocks they use.
+    pub fn summary(&mut self) -> Result<TreeSummary> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let tx = Transaction::new(fs, self.storage.as_mut());
+        let ids: Vec<usize> = tx.reachable_ids()?.into_iter().collect();
+        let nodes = tx.read_nodes(&ids)?;
+        tx.commit()?;
+
+        let mut summary = TreeSummary::default();
+        for node in nodes {
+            match node.filetype() {
+                FileType::File => summary.files += 1,
+                FileType::Dir => summary.dirs += 1,
+                FileType::Symlink => summary.symlinks += 1,
+            }
+            summary.bytes += node.size;
+            summary.blocks += node.block_count();
+        }
+        Ok(summary)
     }
 
     /// Formats the whole storage device with a filesystem capable of handling `node_count` nodes.
-    pub fn mkfs(&mut self, node_count: usize) -> Result<()> {
+    /// Open files are closed, so unless `force` is set, formatting with any open fails.
+    ///
+    /// # Errors
+    /// Returns `Err` if:
+    /// - files are open and `force` is not set
+    /// - `node_count` leaves no room for the root directory next to the null node
+    /// - the metadata regions for `node_count` nodes leave no data block on the device
+    pub fn mkfs(&mut self, node_count: usize, force: bool) -> Result<()> {
+        let options = MkfsOptions {
+            force,
+            ..Default::default()
+        };
+        self.mkfs_with(node_count, options)
+    }
+
+    /// Formats the storage like [Kernel::mkfs], with the given options.
+    /// Zero-filling leaves no stale bytes in the data region, at the cost of writing it whole.
+    pub fn mkfs_with(&mut self, node_count: usize, options: MkfsOptions) -> Result<()> {
         let block_count = self.storage.block_count();
-        self.fs = Some(Filesystem::format(
-            &mut self.storage,
+        if !options.force && !self.open_files.is_empty() {
+            return Err(Error::Busy);
+        }
+        if node_count < 2 {
+            return Err(Error::InvalidNodeCount);
+        }
+        // The regions must be addressable, and the root directory and the backup superblock
+        // need a data block each
+        let superblock =
+            Superblock::try_new(block_count, node_count).ok_or(Error::InvalidNodeCount)?;
+        if superblock.data_start + 1 >= block_count {
+            return Err(Error::InvalidNodeCount);
+        }
+        let mut fs = Filesystem::format(
+            self.storage.as_mut(),
             block_count,
             node_count,
-        ));
+            options.zero_fill,
+        )?;
+        fs.set_dry_run(self.dry_run);
+        self.fs = Some(fs);
+        self.open_files.clear();
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Returns the label of the mounted filesystem.
+    pub fn get_label(&self) -> Result<String> {
+        let fs = self.fs.as_ref().ok_or(Error::FilesystemNotMounted)?;
+        Ok(fs.label().to_string())
+    }
+
+    /// Returns the UUID of the mounted filesystem, generated by [Kernel::mkfs].
+    /// A filesystem mounted with [Kernel::mount_recovery] has a nil UUID.
+    pub fn get_uuid(&self) -> Result<String> {
+        let fs = self.fs.as_ref().ok_or(Error::FilesystemNotMounted)?;
+        Ok(fs.uuid())
+    }
+
+    /// Sets the label of the mounted filesystem.
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        tx.set_label(label)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        Ok(())
+    }
+
+    /// Grows the storage device to `new_size` bytes.
+    pub fn grow_storage(&mut self, new_size: usize) -> Result<()> {
+        self.storage.grow(new_size)?;
+        Ok(())
+    }
+
+    /// Extends the mounted filesystem over the whole storage device.
+    pub fn resize_fs(&mut self) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        let block_count = self.storage.block_count();
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        tx.grow(block_count)?;
+        self.dry_run_summary = Self::finish(tx, self.dry_run)?;
+        Ok(())
+    }
+
+    /// Saves the whole storage device into an image file at `path`.
+    pub fn save_image(&self, path: &str) -> Result<()> {
+        image::save(self.storage.as_ref(), std::path::Path::new(path))?;
+        Ok(())
+    }
+
+    /// Replaces the storage device with one loaded from an image file at `path`.
+    /// The filesystem is unmounted and has to be mounted again.
+    pub fn load_image(&mut self, path: &str) -> Result<()> {
+        let storage = Storage::load_image(path)?;
+        self.release_reservations()?;
+        self.storage = Box::new(storage);
+        self.fs = None;
         self.open_files.clear();
+        self.curr_dir_ptr = NodePtr::root();
+        self.path_cache.invalidate();
         Ok(())
     }
 
     /// Mounts the filesystem.
     pub fn mount(&mut self) -> Result<()> {
-        let fs = Filesystem::mount(&mut self.storage).ok_or(Error::InvalidFilesystem)?;
+        self.release_reservations()?;
+        let mut fs = Filesystem::mount(self.storage.as_ref()).map_err(Self::mount_error)?;
+        fs.set_dry_run(self.dry_run);
         self.fs = Some(fs);
         self.open_files.clear();
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Mounts the filesystem ignoring the on-disk superblock, assuming it was made by
+    /// [Kernel::mkfs] with `block_count` blocks and `node_count` nodes.
+    /// The region offsets follow from these, as `mkfs` lays them out deterministically.
+    ///
+    /// This is meant for salvaging a filesystem whose superblock fails its checksum:
+    /// wrong geometry makes the allocation maps garbage. The label and UUID are not recovered.
+    pub fn mount_recovery(&mut self, block_count: usize, node_count: usize) -> Result<()> {
+        // No blocks would leave the block map empty
+        if block_count == 0 || block_count > self.storage.block_count() {
+            return Err(Error::InvalidFilesystem);
+        }
+        if node_count < 2 {
+            return Err(Error::InvalidNodeCount);
+        }
+        let superblock =
+            Superblock::try_new(block_count, node_count).ok_or(Error::InvalidNodeCount)?;
+        if superblock.data_start + 1 >= block_count {
+            return Err(Error::InvalidNodeCount);
+        }
+        self.release_reservations()?;
+        let mut fs =
+            Filesystem::mount_with(self.storage.as_ref(), superblock).map_err(Self::mount_error)?;
+        fs.set_dry_run(self.dry_run);
+        self.fs = Some(fs);
+        self.open_files.clear();
+        self.path_cache.invalidate();
+        Ok(())
+    }
+
+    /// Reports a filesystem that fails to mount as invalid, unless the storage itself failed.
+    fn mount_error(e: transaction::Error) -> Error {
+        match e {
+            transaction::Error::InvalidSuperblock | transaction::Error::CorruptedMap => {
+                Error::InvalidFilesystem
+            }
+            e => Error::Filesystem(e),
+        }
+    }
+
+    /// Frees the blocks reserved by every open file, so none are leaked
+    /// when the descriptors are dropped without being closed.
+    fn release_reservations(&mut self) -> Result<()> {
+        let Some(fs) = self.fs.as_mut() else {
+            return Ok(());
+        };
+        let mut tx = Transaction::new(fs, self.storage.as_mut());
+        tx.set_time(self.clock.now());
+        for desc in self.open_files.values_mut() {
+            tx.release_reservation(&mut desc.reservation)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns the current time in seconds since the Unix epoch, along with the name of the time source.
+    pub fn now(&self) -> (u64, &'static str) {
+        (self.clock.now(), self.clock.name())
+    }
+
+    /// Stops the clock at `epoch_seconds`, switching to a manual time source.
+    pub fn set_clock(&mut self, epoch_seconds: u64) {
+        self.clock = Box::new(ManualClock::new(epoch_seconds));
+    }
+
+    /// Moves the clock forward by `delta` seconds, switching to a manual time source.
+    pub fn advance_clock(&mut self, delta: u64) {
+        let now = self.clock.now();
+        self.set_clock(now + delta);
+    }
+
+    /// Switches back to the system time source.
+    pub fn reset_clock(&mut self) {
+        self.clock = Box::new(SystemClock);
+    }
+
+    /// Returns the number of path cache hits and misses.
+    pub fn path_cache_stats(&self) -> (usize, usize) {
+        (self.path_cache.hits(), self.path_cache.misses())
+    }
+
+    /// Sets whether freeing already free blocks or nodes is reported as an error.
+    pub fn set_strict_free(&mut self, strict: bool) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        fs.set_strict(strict);
+        Ok(())
+    }
+
+    /// Sets the strategy used to allocate data blocks.
+    pub fn set_alloc_strategy(&mut self, strategy: Strategy) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        fs.set_strategy(strategy);
+        Ok(())
+    }
+
+    /// Sets how many blocks a transaction buffers in memory before flushing
+    /// newly allocated data blocks to storage ahead of its commit.
+    pub fn set_flush_limit(&mut self, limit: usize) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        fs.set_flush_limit(limit);
+        Ok(())
+    }
+
+    /// Registers a callback invoked with the effects of every committed transaction.
+    /// The hook is dropped when the filesystem is formatted or mounted again.
+    pub fn set_commit_hook(&mut self, hook: impl FnMut(&CommitRecord) + 'static) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        fs.set_commit_hook(Some(Box::new(hook)));
+        Ok(())
+    }
+
+    /// Removes the commit callback, if there is one.
+    pub fn clear_commit_hook(&mut self) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        fs.set_commit_hook(None);
         Ok(())
     }
 
+    /// Sets whether destructive operations are only simulated.
+    /// In dry-run mode, their transactions are aborted instead of committed.
+    /// Write-through mode is turned off, as it can't be simulated,
+    /// and transactions don't flush blocks to storage ahead of the commit.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+        self.dry_run_summary = None;
+        if let Some(fs) = self.fs.as_mut() {
+            fs.set_dry_run(dry_run);
+            if dry_run {
+                fs.set_write_through(false);
+            }
+        }
+    }
+
+    /// Sets whether operations write blocks straight to storage, bypassing the transaction buffer.
+    /// Meant as a baseline for measuring the cost of buffering.
+    ///
+    /// Operations are no longer atomic: one that fails midway leaves its writes in place,
+    /// so a rename may leave both names behind. Only allocation maps wait for the commit.
+    /// The mode is reset when the filesystem is formatted or mounted again.
+    ///
+    /// # Errors
+    /// Returns `Err` if enabling it in dry-run mode, which must not write anything.
+    pub fn set_write_through(&mut self, write_through: bool) -> Result<()> {
+        if write_through && self.dry_run {
+            return Err(Error::NotPermitted);
+        }
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        fs.set_write_through(write_through);
+        Ok(())
+    }
+
+    /// Sets whether writes that would run out of extents are refused as a whole
+    /// with [Error::FileTooFragmented], instead of ending short.
+    /// Copying a fragmented file to a new one lays it out in fewer extents.
+    pub fn set_safe_mode(&mut self, safe_mode: bool) {
+        self.safe_mode = safe_mode;
+    }
+
+    /// Sets the size no file may grow past, like `RLIMIT_FSIZE`, or lifts the limit with `None`.
+    ///
+    /// A write crossing the limit ends short at it, and one starting at or past it fails
+    /// with [Error::FileTooBig]. Truncating past it and writes that must be whole,
+    /// such as [Kernel::create_with] and [Kernel::write_struct], fail the same way.
+    pub fn set_max_file_size(&mut self, max_file_size: Option<usize>) {
+        self.max_file_size = max_file_size;
+    }
+
+    /// Sets whether descriptors 0 to 2 are kept for standard streams, so that opened files
+    /// start at 3 like on Unix. Descriptors that are already open are left as they are.
+    pub fn set_reserve_std_fds(&mut self, reserve: bool) {
+        self.reserve_std_fds = reserve;
+    }
+
+    /// Makes the next commit simulate a power loss at `crash_point`, writing only part of its blocks.
+    /// Until the filesystem is mounted again, every commit fails with [Error::IoError],
+    /// and [Kernel::mount] shows what the storage was left with.
+    #[cfg(feature = "crash-points")]
+    pub fn set_crash_point(&mut self, crash_point: Option<transaction::CrashPoint>) -> Result<()> {
+        let fs = self.fs.as_mut().ok_or(Error::FilesystemNotMounted)?;
+        fs.set_crash_point(crash_point);
+        Ok(())
+    }
+
+    /// Sets when reads update the access time of a file.
+    pub fn set_atime_mode(&mut self, mode: AtimeMode) {
+        self.atime_mode = mode;
+    }
+
+    /// Sets whether closing the last descriptor of a file with no links frees it.
+    /// With auto-reaping off, such files stay allocated until [Kernel::reap] is called.
+    pub fn set_auto_reap(&mut self, auto_reap: bool) {
+        self.auto_reap = auto_reap;
+    }
+
+    /// Sets whether writes leave fully-zero, block-aligned chunks as holes
+    /// instead of allocating blocks for them. Allocated blocks are always overwritten.
+    pub fn set_sparse_writes(&mut self, sparse: bool) {
+        self.sparse_writes = sparse;
+    }
+
+    /// Returns what the last simulated operation would have changed, if there was one.
+    pub fn take_dry_run_summary(&mut self) -> Option<Summary> {
+        self.dry_run_summary.take()
+    }
+
+    /// Commits the transaction, or aborts it in dry-run mode.
+    /// Returns a summary of the changes if the transaction was aborted.
+    fn finish(tx: Transaction, dry_run: bool) -> Result<Option<Summary>> {
+        if dry_run {
+            return Ok(Some(tx.abort()));
+        }
+        tx.commit()?;
+        Ok(None)
+    }
+
     /// Opens the file by inserting the file description into the open files table.
     /// Returns the corresponding file descriptor.
     fn open_file(&mut self, desc: FileDescription) -> FileDescriptor {
@@ -276,9 +1613,14 @@ impl Kernel {
     }
 
     /// Returns a file descriptor that can be used to open a file.
+    /// Descriptors of standard streams are skipped if they are reserved.
     fn find_free_fd(&self) -> FileDescriptor {
-        let mut fd = 0;
-        for &occupied_fd in self.open_files.keys() {
+        let mut fd = if self.reserve_std_fds {
+            STD_FD_COUNT
+        } else {
+            0
+        };
+        for &occupied_fd in self.open_files.range(fd..).map(|(fd, _)| fd) {
             if fd < occupied_fd {
                 return fd;
             }
@@ -298,6 +1640,14 @@ pub enum Error {
     InvalidFileDescriptor,
     NotPermitted,
     NotDir,
+    InvalidNodeCount,
+    FileTooFragmented,
+    FileTooBig,
+    UnexpectedEof,
+    Busy,
+    Storage(storage::Error),
+    IoError(CommitError),
+    Image(image::Error),
 }
 
 impl From<transaction::Error> for Error {
@@ -306,6 +1656,33 @@ impl From<transaction::Error> for Error {
     }
 }
 
+impl From<CommitError> for Error {
+    fn from(value: CommitError) -> Self {
+        Self::IoError(value)
+    }
+}
+
+impl From<fs::Error> for Error {
+    fn from(value: fs::Error) -> Self {
+        match value {
+            fs::Error::Transaction(e) => Self::Filesystem(e),
+            fs::Error::Commit(e) => Self::IoError(e),
+        }
+    }
+}
+
+impl From<image::Error> for Error {
+    fn from(value: image::Error) -> Self {
+        Self::Image(value)
+    }
+}
+
+impl From<storage::Error> for Error {
+    fn from(value: storage::Error) -> Self {
+        Self::Storage(value)
+    }
+}
+
 impl From<directory::Error> for Error {
     fn from(value: directory::Error) -> Self {
         Self::Filesystem(transaction::Error::from(value))
@@ -1,2 +1,4 @@
+#[cfg(feature = "fuse")]
+pub mod fuse;
 pub mod hardware;
 pub mod kernel;
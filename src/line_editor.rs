@@ -0,0 +1,236 @@
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Read, Write},
+    path::PathBuf,
+    process::Command,
+};
+
+/// How many lines the history keeps.
+const HISTORY_MAX: usize = 1000;
+
+/// A minimal line editor with history recall.
+pub struct LineEditor {
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    /// Constructs a [LineEditor], loading history from `history_path` if it exists.
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        let history = history_path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|s| s.lines().map(String::from).collect())
+            .unwrap_or_default();
+        Self {
+            history,
+            history_path,
+        }
+    }
+
+    /// Reads a line after printing `prompt`.
+    /// Returns `None` on end of input.
+    ///
+    /// Line editing is only available when stdin is a terminal that can be put into raw mode,
+    /// otherwise lines are read as is.
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+
+        // Without raw mode (e.g. `stty` is unavailable) lines are read as is too
+        let raw = if io::stdin().is_terminal() {
+            RawMode::enable().ok()
+        } else {
+            None
+        };
+        let Some(_raw) = raw else {
+            let mut input = String::new();
+            if io::stdin().lock().read_line(&mut input)? == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(input));
+        };
+
+        let line = self.edit(prompt)?;
+        println!();
+        if let Some(line) = &line {
+            self.add_history(line);
+        }
+        Ok(line)
+    }
+
+    /// Appends the line to the history, skipping blank lines and repeats.
+    pub fn add_history(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || self.history.last().is_some_and(|l| l == line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > HISTORY_MAX {
+            self.history.remove(0);
+        }
+    }
+
+    /// Writes the history to its file, if there is one.
+    pub fn save_history(&self) -> io::Result<()> {
+        let Some(path) = &self.history_path else {
+            return Ok(());
+        };
+        if self.history.is_empty() {
+            return Ok(());
+        }
+        let mut contents = self.history.join("\n");
+        contents.push('\n');
+        fs::write(path, contents)
+    }
+
+    /// Runs the editing loop over raw terminal input.
+    fn edit(&self, prompt: &str) -> io::Result<Option<String>> {
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Position in history, `history.len()` being the line under edit
+        let mut index = self.history.len();
+        let mut draft: Vec<char> = Vec::new();
+
+        let mut stdin = io::stdin().lock();
+        loop {
+            let Some(byte) = read_byte(&mut stdin)? else {
+                return Ok(None);
+            };
+            match byte {
+                b'\r' | b'\n' => break,
+                // Ctrl-D
+                4 => {
+                    if line.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                // Ctrl-C
+                3 => {
+                    line.clear();
+                    cursor = 0;
+                }
+                // Backspace
+                8 | 127 => {
+                    if cursor > 0 {
+                        cursor -= 1;
+                        line.remove(cursor);
+                    }
+                }
+                // Escape sequence
+                0x1b => {
+                    if read_byte(&mut stdin)? != Some(b'[') {
+                        continue;
+                    }
+                    match read_byte(&mut stdin)? {
+                        // Up
+                        Some(b'A') if index > 0 => {
+                            if index == self.history.len() {
+                                draft = line.clone();
+                            }
+                            index -= 1;
+                            line = self.history[index].chars().collect();
+                            cursor = line.len();
+                        }
+                        // Down
+                        Some(b'B') if index < self.history.len() => {
+                            index += 1;
+                            line = match self.history.get(index) {
+                                Some(l) => l.chars().collect(),
+                                None => draft.clone(),
+                            };
+                            cursor = line.len();
+                        }
+                        // Right
+                        Some(b'C') => cursor = (cursor + 1).min(line.len()),
+                        // Left
+                        Some(b'D') => cursor = cursor.saturating_sub(1),
+                        Some(b'H') => cursor = 0,
+                        Some(b'F') => cursor = line.len(),
+                        // Delete
+                        Some(b'3') => {
+                            let tilde = read_byte(&mut stdin)? == Some(b'~');
+                            if tilde && cursor < line.len() {
+                                line.remove(cursor);
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                _ if byte.is_ascii_control() => (),
+                _ => {
+                    let c = read_char(&mut stdin, byte)?;
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+            }
+            redraw(prompt, &line, cursor)?;
+        }
+        Ok(Some(line.into_iter().collect()))
+    }
+}
+
+/// Redraws the line, placing the terminal cursor at `cursor`.
+fn redraw(prompt: &str, line: &[char], cursor: usize) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let text: String = line.iter().collect();
+    write!(stdout, "\r\x1b[K{}{}", prompt, text)?;
+    let back = line.len() - cursor;
+    if back > 0 {
+        write!(stdout, "\x1b[{}D", back)?;
+    }
+    stdout.flush()
+}
+
+/// Reads a single byte, returning `None` on end of input.
+fn read_byte(reader: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+    match reader.read(&mut buf)? {
+        0 => Ok(None),
+        _ => Ok(Some(buf[0])),
+    }
+}
+
+/// Reads the rest of a UTF-8 encoded character that starts with `first`.
+fn read_char(reader: &mut impl Read, first: u8) -> io::Result<char> {
+    let len = match first {
+        0xF0.. => 4,
+        0xE0.. => 3,
+        0xC0.. => 2,
+        _ => 1,
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        bytes.extend(read_byte(reader)?);
+    }
+    let c = str::from_utf8(&bytes)
+        .ok()
+        .and_then(|s| s.chars().next())
+        .unwrap_or(char::REPLACEMENT_CHARACTER);
+    Ok(c)
+}
+
+/// Puts the terminal into raw mode, restoring it when dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        stty(&["-icanon", "-echo", "-isig", "min", "1"])?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&["icanon", "echo", "isig"]);
+    }
+}
+
+/// Runs `stty` against the terminal attached to stdin.
+fn stty(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("stty").args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::other("'stty' failed"));
+    }
+    Ok(())
+}
@@ -1,6 +1,16 @@
+use line_editor::LineEditor;
 use os_lab_4::hardware::storage::Storage;
 use os_lab_4::kernel::Kernel;
-use std::io::{self, Write};
+use os_lab_4::kernel::file::{AtimeMode, MkfsOptions, OpenOptions, SortKey, SortOrder};
+use os_lab_4::kernel::fs::alloc_map::{AllocFlag, Strategy};
+use os_lab_4::kernel::fs::directory::DirEntryName;
+use os_lab_4::kernel::fs::node::FileType;
+use std::path::PathBuf;
+
+mod line_editor;
+
+/// File the shell history is persisted to across sessions.
+const HISTORY_FILE: &str = ".fs_history";
 
 fn main() {
     // Initialize a 1 MiB in-memory storage
@@ -11,19 +21,25 @@ fn main() {
     println!("Filesystem shell opened.");
     println!("Type 'help' for commands.");
 
+    let mut editor = LineEditor::new(Some(PathBuf::from(HISTORY_FILE)));
+
+    // Consecutive writes to one fd are buffered here while batching is on
+    let mut batching = false;
+    let mut batch: Option<WriteBatch> = None;
+
+    // Read input
     loop {
-        // Print prompt
-        print!("> ");
-        io::stdout().flush().unwrap();
-
-        // Read input
-        let mut input = String::new();
-        if io::stdin().read_line(&mut input).unwrap() == 0 {
-            break;
-        }
+        let input = match editor.read_line("> ") {
+            Ok(Some(input)) => input,
+            Ok(None) => break,
+            Err(e) => {
+                println!("Failed to read input: {}", e);
+                break;
+            }
+        };
 
         // Parse command
-        let parts: Vec<&str> = input.trim().split_whitespace().collect();
+        let parts: Vec<&str> = input.split_whitespace().collect();
         if parts.is_empty() {
             continue;
         }
@@ -31,62 +47,173 @@ fn main() {
         let command = parts[0];
         let args = &parts[1..];
 
+        // Anything but another write to the same fd commits the pending batch first,
+        // so every other command sees the buffered data
+        let batch_fd = batch.as_ref().map(|batch| batch.fd);
+        if command != "write" || args.first().and_then(|s| s.parse().ok()) != batch_fd {
+            commit_batch(&mut kernel, &mut batch);
+        }
+
         // Execute the command as a system call
         match command {
             "mkfs" => {
-                if let Some(n) = args.get(0).and_then(|s| s.parse().ok()) {
-                    match kernel.mkfs(n) {
+                let mut options = MkfsOptions::default();
+                let mut args = args;
+                while let Some((flag, rest)) = args.split_first() {
+                    match *flag {
+                        "-f" => options.force = true,
+                        "-z" => options.zero_fill = true,
+                        _ => break,
+                    }
+                    args = rest;
+                }
+                if let Some(n) = args.first().and_then(|s| s.parse().ok()) {
+                    match kernel.mkfs_with(n, options) {
                         Ok(_) => println!("Filesystem formatted with {} nodes.", n),
                         Err(e) => println!("Error: {:?}", e),
                     }
                 } else {
-                    println!("Usage: mkfs <node_count>");
+                    println!("Usage: mkfs [-f] [-z] <node_count>");
+                }
+            }
+            "saveimg" => {
+                if let Some(path) = args.first() {
+                    println!("{:?}", kernel.save_image(path));
+                } else {
+                    println!("Usage: saveimg <file>");
+                }
+            }
+            "loadimg" => {
+                if let Some(path) = args.first() {
+                    println!("{:?}", kernel.load_image(path));
+                } else {
+                    println!("Usage: loadimg <file>");
+                }
+            }
+            "resizefs" => {
+                if let Some(size) = args.first().and_then(|s| s.parse().ok()) {
+                    match kernel.grow_storage(size).and_then(|_| kernel.resize_fs()) {
+                        Ok(_) => println!("Filesystem resized to {} bytes.", size),
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: resizefs <size>");
                 }
             }
+            "label" => match args.first() {
+                Some(label) => println!("{:?}", kernel.set_label(label)),
+                None => match kernel.get_label() {
+                    Ok(label) => println!("Label: {}", label),
+                    Err(e) => println!("Error: {:?}", e),
+                },
+            },
             "mount" => match kernel.mount() {
-                Ok(_) => println!("Filesystem mounted."),
+                Ok(_) => {
+                    println!("Filesystem mounted.");
+                    if let (Ok(label), Ok(uuid)) = (kernel.get_label(), kernel.get_uuid()) {
+                        println!("Label: {}", label);
+                        println!("UUID: {}", uuid);
+                    }
+                }
                 Err(e) => println!("Error: {:?}", e),
             },
+            "recover" => {
+                let block_count = args.first().and_then(|s| s.parse().ok());
+                let node_count = args.get(1).and_then(|s| s.parse().ok());
+                if let (Some(block_count), Some(node_count)) = (block_count, node_count) {
+                    match kernel.mount_recovery(block_count, node_count) {
+                        Ok(_) => println!("Filesystem mounted with forced geometry."),
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: recover <block_count> <node_count>");
+                }
+            }
             "create" => {
-                if let Some(path) = args.get(0) {
-                    println!("{:?}", kernel.create(path));
+                if let Some(path) = args.first() {
+                    if check_name_len(path) {
+                        println!("{:?}", kernel.create(path));
+                    }
                 } else {
                     println!("Usage: create <path>");
                 }
             }
+            "put" => {
+                if args.len() >= 2 {
+                    // Join the rest of the arguments as data
+                    let data = args[1..].join(" ");
+                    println!("{:?}", kernel.create_with(args[0], data.as_bytes()));
+                } else {
+                    println!("Usage: put <path> <data>");
+                }
+            }
+            "safewrite" => {
+                if args.len() >= 2 {
+                    // Join the rest of the arguments as data
+                    let data = args[1..].join(" ");
+                    println!("{:?}", kernel.safe_write(args[0], data.as_bytes()));
+                } else {
+                    println!("Usage: safewrite <path> <data>");
+                }
+            }
             "mkdir" => {
-                if let Some(path) = args.get(0) {
-                    println!("{:?}", kernel.mkdir(path));
+                if let Some(path) = args.first() {
+                    if check_name_len(path) {
+                        println!("{:?}", kernel.mkdir(path));
+                    }
                 } else {
                     println!("Usage: mkdir <path>");
                 }
             }
             "rmdir" => {
-                if let Some(path) = args.get(0) {
+                if let Some(path) = args.first() {
                     println!("{:?}", kernel.rmdir(path));
                 } else {
                     println!("Usage: rmdir <path>");
                 }
             }
+            "compact" => {
+                let path = args.first().copied().unwrap_or(".");
+                println!("{:?}", kernel.compact(path));
+            }
+            "compact-nodes" => match kernel.compact_nodes() {
+                Ok(moved) => println!("Moved {} nodes.", moved),
+                Err(e) => println!("Error: {:?}", e),
+            },
             "cd" => {
-                if let Some(path) = args.get(0) {
+                if let Some(path) = args.first() {
                     println!("{:?}", kernel.cd(path));
                 } else {
                     println!("Usage: cd <path>");
                 }
             }
             "open" => {
-                if let Some(path) = args.get(0) {
-                    match kernel.open(path) {
+                let (create, exclusive, args) = match args.first() {
+                    Some(&"-c") => (true, false, &args[1..]),
+                    Some(&"-x") => (true, true, &args[1..]),
+                    _ => (false, false, args),
+                };
+                let preferred_extent = match args.get(1).map(|s| s.parse()) {
+                    Some(Ok(n)) => Some(n),
+                    Some(Err(_)) => None,
+                    None => Some(0),
+                };
+                if let (Some(path), Some(preferred_extent)) = (args.first(), preferred_extent) {
+                    let options = OpenOptions {
+                        preferred_extent,
+                        create,
+                        exclusive,
+                    };
+                    match kernel.open_with(path, options) {
                         Ok(fd) => println!("File opened.\nfd: {}", fd),
                         Err(e) => println!("Error: {:?}", e),
                     }
                 } else {
-                    println!("Usage: open <path>");
+                    println!("Usage: open [-c|-x] <path> [preferred_extent]");
                 }
             }
             "close" => {
-                if let Some(fd) = args.get(0).and_then(|s| s.parse().ok()) {
+                if let Some(fd) = args.first().and_then(|s| s.parse().ok()) {
                     println!("{:?}", kernel.close(fd));
                 } else {
                     println!("Usage: close <fd>");
@@ -98,11 +225,12 @@ fn main() {
                     let size = args[1].parse().unwrap_or(0);
                     let mut buf = vec![0u8; size];
 
-                    match kernel.read(fd, &mut buf) {
-                        Ok(bytes_read) => {
+                    match kernel.read_ex(fd, &mut buf) {
+                        Ok((bytes_read, eof)) => {
                             // Try to print as string, otherwise print bytes
                             let output = String::from_utf8_lossy(&buf[..bytes_read]);
-                            println!("Read {} bytes: {:?}", bytes_read, output);
+                            let eof = if eof { " (EOF)" } else { "" };
+                            println!("Read {} bytes: {:?}{}", bytes_read, output, eof);
                         }
                         Err(e) => println!("Error: {:?}", e),
                     }
@@ -110,19 +238,81 @@ fn main() {
                     println!("Usage: read <fd> <size>");
                 }
             }
+            "peek" => {
+                let fd = args.first().and_then(|s| s.parse().ok());
+                let size = args.get(1).and_then(|s| s.parse().ok());
+                if let (Some(fd), Some(size)) = (fd, size) {
+                    match kernel.peek(fd, size) {
+                        Ok(data) => println!(
+                            "Peeked {} bytes: {:?}",
+                            data.len(),
+                            String::from_utf8_lossy(&data)
+                        ),
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: peek <fd> <size>");
+                }
+            }
             "write" => {
-                if args.len() >= 2 {
+                if !args.is_empty() {
                     let fd = args[0].parse().unwrap_or(usize::MAX);
                     // Join the rest of the arguments as data
                     let data = args[1..].join(" ");
-                    match kernel.write(fd, data.as_bytes()) {
-                        Ok(bytes_written) => println!("Written {} bytes.", bytes_written),
+                    if batching {
+                        match (&mut batch, kernel.fd_stats(fd)) {
+                            (Some(batch), _) => batch.data.extend_from_slice(data.as_bytes()),
+                            (None, Ok(_)) => {
+                                batch = Some(WriteBatch {
+                                    fd,
+                                    data: data.into_bytes(),
+                                })
+                            }
+                            (None, Err(e)) => println!("Error: {:?}", e),
+                        }
+                        if let Some(batch) = &batch {
+                            println!("Buffered {} bytes.", batch.data.len());
+                        }
+                    } else {
+                        match kernel.write(fd, data.as_bytes()) {
+                            Ok(bytes_written) => println!("Written {} bytes.", bytes_written),
+                            Err(e) => println!("Error: {:?}", e),
+                        }
+                    }
+                } else {
+                    println!("Usage: write <fd> [data]");
+                }
+            }
+            "copyrange" => {
+                let nums: Vec<usize> = args.iter().filter_map(|s| s.parse().ok()).collect();
+                if let [src_fd, dst_fd, len] = nums[..] {
+                    // Copy from and to the current offsets
+                    let offsets = kernel
+                        .fd_stats(src_fd)
+                        .and_then(|src| Ok((src.offset, kernel.fd_stats(dst_fd)?.offset)));
+                    let result = offsets.and_then(|(src_offset, dst_offset)| {
+                        kernel.copy_file_range(src_fd, src_offset, dst_fd, dst_offset, len)
+                    });
+                    match result {
+                        Ok(copied) => println!("Copied {} bytes.", copied),
                         Err(e) => println!("Error: {:?}", e),
                     }
                 } else {
-                    println!("Usage: write <fd> <data>");
+                    println!("Usage: copyrange <src_fd> <dst_fd> <len>");
                 }
             }
+            "fdstat" => match args.first().and_then(|s| s.parse().ok()) {
+                Some(fd) => match kernel.fd_stats(fd) {
+                    Ok(stats) => {
+                        println!("Node id: {}", stats.node_id);
+                        println!("Offset: {}", stats.offset);
+                        println!("Bytes read: {}", stats.bytes_read);
+                        println!("Bytes written: {}", stats.bytes_written);
+                    }
+                    Err(e) => println!("Error: {:?}", e),
+                },
+                None => println!("Usage: fdstat <fd>"),
+            },
             "seek" => {
                 if args.len() >= 2 {
                     let fd = args[0].parse().unwrap_or(usize::MAX);
@@ -139,8 +329,27 @@ fn main() {
                     println!("Usage: link <old_path> <new_path>");
                 }
             }
+            "linktree" => {
+                let recursive = args.first() == Some(&"-r");
+                let args = if recursive { &args[1..] } else { args };
+                if args.len() >= 2 {
+                    match kernel.link_tree(args[0], args[1], recursive) {
+                        Ok(count) => println!("Created {} links.", count),
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: linktree [-r] <src> <dst>");
+                }
+            }
+            "mv" => {
+                if args.len() == 2 {
+                    println!("{:?}", kernel.rename(args[0], args[1]));
+                } else {
+                    println!("Usage: mv <old_path> <new_path>");
+                }
+            }
             "unlink" => {
-                if let Some(path) = args.get(0) {
+                if let Some(path) = args.first() {
                     println!("{:?}", kernel.unlink(path));
                 } else {
                     println!("Usage: unlink <path>");
@@ -162,34 +371,497 @@ fn main() {
                     println!("Usage: truncate <path> <size>");
                 }
             }
+            "ftruncate" => {
+                let fd = args.first().and_then(|s| s.parse().ok());
+                let size = args.get(1).and_then(|s| s.parse().ok());
+                if let (Some(fd), Some(size)) = (fd, size) {
+                    println!("{:?}", kernel.ftruncate(fd, size));
+                } else {
+                    println!("Usage: ftruncate <fd> <size>");
+                }
+            }
+            "punch" => {
+                if args.len() >= 3 {
+                    let path = args[0];
+                    let offset = args[1].parse().unwrap_or(0);
+                    let len = args[2].parse().unwrap_or(0);
+                    println!("{:?}", kernel.punch_hole(path, offset, len));
+                } else {
+                    println!("Usage: punch <path> <offset> <len>");
+                }
+            }
+            "test" => {
+                // Prints an exit-style status: 0 if the check holds, 1 otherwise
+                if args.len() == 2 {
+                    let holds = match args[0] {
+                        "-e" => Some(kernel.exists(args[1])),
+                        "-d" => Some(kernel.is_dir(args[1]).unwrap_or(false)),
+                        "-f" => Some(
+                            kernel
+                                .stat(args[1])
+                                .is_ok_and(|stats| stats.filetype == FileType::File),
+                        ),
+                        _ => None,
+                    };
+                    match holds {
+                        Some(holds) => println!("{}", if holds { 0 } else { 1 }),
+                        None => println!("Usage: test -e|-d|-f <path>"),
+                    }
+                } else {
+                    println!("Usage: test -e|-d|-f <path>");
+                }
+            }
+            "utimes" => {
+                let atime = args.get(1).and_then(|s| s.parse().ok());
+                let mtime = args.get(2).and_then(|s| s.parse().ok());
+                if let (Some(path), Some(atime), Some(mtime)) = (args.first(), atime, mtime) {
+                    println!("{:?}", kernel.utimes(path, atime, mtime));
+                } else {
+                    println!("Usage: utimes <path> <atime> <mtime>");
+                }
+            }
             "stat" => {
-                if let Some(path) = args.get(0) {
-                    match kernel.stat(path) {
+                // '-L' follows a symlink (default), '-P' describes the symlink itself
+                let physical = args.first() == Some(&"-P");
+                let args = match args.first() {
+                    Some(&"-L") | Some(&"-P") => &args[1..],
+                    _ => args,
+                };
+                if let Some(path) = args.first() {
+                    let stats = if physical {
+                        kernel.lstat(path)
+                    } else {
+                        kernel.stat(path)
+                    };
+                    match stats {
                         Ok(stats) => {
                             println!("File: {}", path);
                             println!("Type: {:?}", stats.filetype);
                             println!("Size: {}", stats.size);
                             println!("Links: {}", stats.link_count);
                             println!("Blocks: {}", stats.block_count);
+                            println!("Extents: {}", stats.extent_count);
+                            println!("Inline: {}", stats.inline);
+                            println!("Modified: {}", stats.mtime);
+                            println!("Accessed: {}", stats.atime);
                             println!("Node id: {}", stats.node_id);
                         }
                         Err(e) => println!("Error: {:?}", e),
                     }
                 } else {
-                    println!("Usage: stat <path>");
+                    println!("Usage: stat [-L|-P] <path>");
                 }
             }
             "ls" => {
-                let path = args.get(0).copied().unwrap_or(".");
-                match kernel.ls(path) {
-                    Ok(list) => {
-                        for (name, node) in list {
-                            println!("{} {}", node, name);
+                let mut show_hidden = false;
+                let mut long = false;
+                let mut order = SortOrder::default();
+                let mut path = ".";
+                let mut valid = true;
+                let mut args = args.iter();
+                while let Some(&arg) = args.next() {
+                    match arg {
+                        "--sort" => match args.next() {
+                            Some(&"name") => order.key = SortKey::Name,
+                            Some(&"size") => order.key = SortKey::Size,
+                            Some(&"node") => order.key = SortKey::Node,
+                            _ => valid = false,
+                        },
+                        _ if arg.starts_with('-') => {
+                            show_hidden |= arg.contains('a');
+                            long |= arg.contains('l');
+                            order.descending |= arg.contains('r');
+                        }
+                        _ => path = arg,
+                    }
+                }
+                if !valid {
+                    println!("Usage: ls [-alr] [--sort name|size|node] [path]");
+                } else if long {
+                    match kernel.ls_long(path, show_hidden, order) {
+                        Ok(list) => {
+                            for (name, stats) in list {
+                                println!(
+                                    "{:<8} {:>3} {:>10} {:>5} {}",
+                                    format!("{:?}", stats.filetype),
+                                    stats.link_count,
+                                    stats.size,
+                                    stats.block_count,
+                                    name
+                                );
+                            }
+                        }
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    match kernel.ls(path, show_hidden, order) {
+                        Ok(list) => {
+                            for (name, node) in list {
+                                println!("{} {}", node, name);
+                            }
+                        }
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                }
+            }
+            "fsck" => {
+                let repair = args.first() == Some(&"--repair");
+                match kernel.fsck(repair) {
+                    Ok(report) => {
+                        println!("Orphaned nodes: {:?}", report.orphans);
+                        if repair {
+                            println!("Recovered into /lost+found: {:?}", report.recovered);
                         }
                     }
                     Err(e) => println!("Error: {:?}", e),
                 }
             }
+            "check-nodes" => match kernel.reachable_nodes() {
+                Ok((reachable, allocated)) => {
+                    println!("Reachable nodes: {}", reachable);
+                    println!("Allocated nodes: {}", allocated);
+                }
+                Err(e) => println!("Error: {:?}", e),
+            },
+            "zombies" => match kernel.zombies() {
+                Ok(zombies) if zombies.is_empty() => println!("No zombie nodes."),
+                Ok(zombies) => {
+                    for id in zombies {
+                        println!("{}", id);
+                    }
+                }
+                Err(e) => println!("Error: {:?}", e),
+            },
+            "freemap" => match kernel.freemap() {
+                Ok(runs) => {
+                    for (flag, start, len) in runs {
+                        let state = match flag {
+                            AllocFlag::Free => "FREE",
+                            AllocFlag::Used => "USED",
+                        };
+                        println!("{} {}..{}", state, start, start + len);
+                    }
+                }
+                Err(e) => println!("Error: {:?}", e),
+            },
+            "owner" => match args.first().and_then(|s| s.parse().ok()) {
+                Some(block_id) => match kernel.block_owner(block_id) {
+                    Ok(Some(node_id)) => println!("Node {}", node_id),
+                    Ok(None) => {
+                        // Without an owner, a used block holds metadata
+                        let is_free = kernel.freemap().is_ok_and(|runs| {
+                            runs.iter().any(|&(flag, start, len)| {
+                                flag == AllocFlag::Free && (start..start + len).contains(&block_id)
+                            })
+                        });
+                        println!("{}", if is_free { "Free" } else { "Metadata" });
+                    }
+                    Err(e) => println!("Error: {:?}", e),
+                },
+                None => println!("Usage: owner <block>"),
+            },
+            "summary" => match kernel.summary() {
+                Ok(summary) => {
+                    println!("Files: {}", summary.files);
+                    println!("Directories: {}", summary.dirs);
+                    println!("Symlinks: {}", summary.symlinks);
+                    println!("Bytes: {}", summary.bytes);
+                    println!("Blocks: {}", summary.blocks);
+                }
+                Err(e) => println!("Error: {:?}", e),
+            },
+            "nodes" => match kernel.list_nodes() {
+                Ok(nodes) => {
+                    println!(
+                        "{:>6} {:<8} {:>10} {:>6} {:>7}",
+                        "ID", "TYPE", "SIZE", "LINKS", "BLOCKS"
+                    );
+                    for (id, stats) in nodes {
+                        println!(
+                            "{:>6} {:<8} {:>10} {:>6} {:>7}",
+                            id,
+                            format!("{:?}", stats.filetype),
+                            stats.size,
+                            stats.link_count,
+                            stats.block_count
+                        );
+                    }
+                }
+                Err(e) => println!("Error: {:?}", e),
+            },
+            "batch" => match args.first() {
+                Some(&"on") => batching = true,
+                Some(&"off") => batching = false,
+                _ => println!("Usage: batch <on|off>"),
+            },
+            // The pending batch was already committed above
+            "sync" => (),
+            "dryrun" => match args.first() {
+                Some(&"on") => kernel.set_dry_run(true),
+                Some(&"off") => kernel.set_dry_run(false),
+                _ => println!("Usage: dryrun <on|off>"),
+            },
+            "sparse" => match args.first() {
+                Some(&"on") => kernel.set_sparse_writes(true),
+                Some(&"off") => kernel.set_sparse_writes(false),
+                _ => println!("Usage: sparse <on|off>"),
+            },
+            "maxsize" => match args.first() {
+                Some(&"off") => kernel.set_max_file_size(None),
+                Some(size) => match size.parse() {
+                    Ok(size) => kernel.set_max_file_size(Some(size)),
+                    Err(_) => println!("Usage: maxsize <bytes|off>"),
+                },
+                None => println!("Usage: maxsize <bytes|off>"),
+            },
+            "atime" => match args.first() {
+                Some(&"strict") => kernel.set_atime_mode(AtimeMode::Strict),
+                Some(&"relatime") => kernel.set_atime_mode(AtimeMode::Relative),
+                Some(&"noatime") => kernel.set_atime_mode(AtimeMode::Never),
+                _ => println!("Usage: atime <strict|relatime|noatime>"),
+            },
+            "autoreap" => match args.first() {
+                Some(&"on") => kernel.set_auto_reap(true),
+                Some(&"off") => kernel.set_auto_reap(false),
+                _ => println!("Usage: autoreap <on|off>"),
+            },
+            "reap" => match kernel.reap() {
+                Ok(freed) => println!("Freed {} nodes.", freed),
+                Err(e) => println!("Error: {:?}", e),
+            },
+            "stdfds" => match args.first() {
+                Some(&"on") => kernel.set_reserve_std_fds(true),
+                Some(&"off") => kernel.set_reserve_std_fds(false),
+                _ => println!("Usage: stdfds <on|off>"),
+            },
+            "safemode" => match args.first() {
+                Some(&"on") => kernel.set_safe_mode(true),
+                Some(&"off") => kernel.set_safe_mode(false),
+                _ => println!("Usage: safemode <on|off>"),
+            },
+            "strict" => match args.first() {
+                Some(&"on") => println!("{:?}", kernel.set_strict_free(true)),
+                Some(&"off") => println!("{:?}", kernel.set_strict_free(false)),
+                _ => println!("Usage: strict <on|off>"),
+            },
+            "commitlog" => match args.first() {
+                Some(&"on") => println!(
+                    "{:?}",
+                    kernel.set_commit_hook(|record| {
+                        println!(
+                            "Committed blocks {:?}, nodes {:?}",
+                            record.blocks, record.nodes
+                        )
+                    })
+                ),
+                Some(&"off") => println!("{:?}", kernel.clear_commit_hook()),
+                _ => println!("Usage: commitlog <on|off>"),
+            },
+            "alloc" => match args.first() {
+                Some(&"first") => println!("{:?}", kernel.set_alloc_strategy(Strategy::FirstFit)),
+                Some(&"spread") => println!("{:?}", kernel.set_alloc_strategy(Strategy::Spread)),
+                _ => println!("Usage: alloc <first|spread>"),
+            },
+            "writethrough" => match args.first() {
+                Some(&"on") => println!("{:?}", kernel.set_write_through(true)),
+                Some(&"off") => println!("{:?}", kernel.set_write_through(false)),
+                _ => println!("Usage: writethrough <on|off>"),
+            },
+            "flushlimit" => match args.first().and_then(|s| s.parse().ok()) {
+                Some(limit) => println!("{:?}", kernel.set_flush_limit(limit)),
+                None => println!("Usage: flushlimit <blocks>"),
+            },
+            "setxattr" => {
+                if args.len() >= 3 {
+                    let value = args[2..].join(" ");
+                    println!("{:?}", kernel.setxattr(args[0], args[1], value.as_bytes()));
+                } else {
+                    println!("Usage: setxattr <path> <name> <value>");
+                }
+            }
+            "getxattr" => {
+                if args.len() == 2 {
+                    match kernel.getxattr(args[0], args[1]) {
+                        Ok(Some(value)) => println!("{:?}", String::from_utf8_lossy(&value)),
+                        Ok(None) => println!("No such attribute."),
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: getxattr <path> <name>");
+                }
+            }
+            "listxattr" => {
+                if let Some(path) = args.first() {
+                    match kernel.listxattr(path) {
+                        Ok(names) => {
+                            for name in names {
+                                println!("{}", name);
+                            }
+                        }
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: listxattr <path>");
+                }
+            }
+            "hash" => {
+                if let Some(path) = args.first() {
+                    match kernel.hash_file(path) {
+                        Ok(digest) => {
+                            let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+                            println!("{}", hex);
+                        }
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: hash <path>");
+                }
+            }
+            "verify" => {
+                if args.len() == 2 {
+                    match parse_digest(args[1]) {
+                        Some(expected) => match kernel.verify_file(args[0], &expected) {
+                            Ok(true) => println!("Match."),
+                            Ok(false) => println!("Mismatch."),
+                            Err(e) => println!("Error: {:?}", e),
+                        },
+                        None => println!("Invalid digest: expected 64 hex digits."),
+                    }
+                } else {
+                    println!("Usage: verify <path> <hex>");
+                }
+            }
+            "tree" => {
+                let (max_depth, args) = match args {
+                    ["--depth", n, rest @ ..] => (n.parse().ok(), rest),
+                    _ => (Some(usize::MAX), args),
+                };
+                match (max_depth, args) {
+                    (Some(max_depth), [] | [_]) => {
+                        let path = args.first().copied().unwrap_or(".");
+                        match kernel.walk(path) {
+                            Ok(entries) => print_tree(path, &entries, max_depth),
+                            Err(e) => println!("Error: {:?}", e),
+                        }
+                    }
+                    _ => println!("Usage: tree [--depth N] [path]"),
+                }
+            }
+            "newer" => {
+                if let Some(since) = args.first().and_then(|s| s.parse().ok()) {
+                    let path = args.get(1).copied().unwrap_or(".");
+                    match kernel.find_modified_since(path, since) {
+                        Ok(paths) => {
+                            for path in paths {
+                                println!("{}", path);
+                            }
+                        }
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: newer <since> [path]");
+                }
+            }
+            "merge" => {
+                let overwrite = args.first() == Some(&"-f");
+                let args = if overwrite { &args[1..] } else { args };
+                if args.len() == 2 {
+                    println!("{:?}", kernel.merge_dirs(args[0], args[1], overwrite));
+                } else {
+                    println!("Usage: merge [-f] <src> <dst>");
+                }
+            }
+            "fiemap" => {
+                let offset = args.get(1).map_or(Some(0), |s| s.parse().ok());
+                let len = args.get(2).map_or(Some(usize::MAX), |s| s.parse().ok());
+                if let (Some(path), Some(offset), Some(len)) = (args.first(), offset, len) {
+                    match kernel.fiemap(path, offset, len) {
+                        Ok(mappings) => {
+                            println!("{:>10} {:>10} {:>10}", "Offset", "Length", "Block");
+                            for m in mappings {
+                                let block = if m.is_hole {
+                                    "hole".to_string()
+                                } else if m.inline {
+                                    "inline".to_string()
+                                } else {
+                                    m.physical_block.to_string()
+                                };
+                                println!("{:>10} {:>10} {:>10}", m.logical_offset, m.length, block);
+                            }
+                        }
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: fiemap <path> [offset] [len]");
+                }
+            }
+            "links" => {
+                if let Some(path) = args.first() {
+                    match kernel.find_links(path) {
+                        Ok(links) => {
+                            for link in links {
+                                println!("{}", link);
+                            }
+                        }
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: links <path>");
+                }
+            }
+            "resolve" => {
+                if let Some(path) = args.first() {
+                    match kernel.resolve_trace(path) {
+                        Ok(trace) => {
+                            for step in trace {
+                                let follow = if step.is_symlink { " (symlink)" } else { "" };
+                                println!(
+                                    "{}{:?}: dir {} -> node {}{}",
+                                    "  ".repeat(step.depth),
+                                    step.name,
+                                    step.dir_id,
+                                    step.node_id,
+                                    follow
+                                );
+                            }
+                        }
+                        Err(e) => println!("Error: {:?}", e),
+                    }
+                } else {
+                    println!("Usage: resolve <path>");
+                }
+            }
+            "dirnode" => match args.first() {
+                Some(path) => match kernel.dirname_node(path) {
+                    Ok(id) => println!("Node id: {}", id),
+                    Err(e) => println!("Error: {:?}", e),
+                },
+                None => println!("Usage: dirnode <path>"),
+            },
+            "pathcache" => {
+                let (hits, misses) = kernel.path_cache_stats();
+                println!("Hits: {}", hits);
+                println!("Misses: {}", misses);
+            }
+            "time" => {
+                let (time, source) = kernel.now();
+                println!("Time: {} ({})", time, source);
+            }
+            "settime" => match args.first() {
+                Some(&"system") => kernel.reset_clock(),
+                Some(arg) => match arg.parse() {
+                    Ok(time) => kernel.set_clock(time),
+                    Err(_) => println!("Usage: settime <seconds|system>"),
+                },
+                None => println!("Usage: settime <seconds|system>"),
+            },
+            "advancetime" => {
+                if let Some(delta) = args.first().and_then(|s| s.parse().ok()) {
+                    kernel.advance_clock(delta);
+                } else {
+                    println!("Usage: advancetime <seconds>");
+                }
+            }
             "clear" => {
                 print!("\x1b[2J\x1b[1;1H");
             }
@@ -197,23 +869,101 @@ fn main() {
             "help" => {
                 println!("COMMANDS");
                 let commands = [
-                    ("mkfs <nodes>", "format filesystem"),
+                    (
+                        "mkfs [-f] [-z] <nodes>",
+                        "format filesystem (-f even with open files, -z zeroing data blocks)",
+                    ),
                     ("mount", "mount filesystem"),
+                    ("recover <blocks> <nodes>", "mount with forced geometry"),
+                    ("label [name]", "display or set filesystem label"),
+                    ("resizefs <size>", "grow storage and filesystem"),
+                    ("saveimg <file>", "save storage to an image file"),
+                    ("loadimg <file>", "load storage from an image file"),
                     ("create <path>", "create a file"),
+                    ("put <path> <string>", "create a file with contents"),
+                    (
+                        "safewrite <path> <string>",
+                        "replace file contents atomically",
+                    ),
                     ("mkdir <path>", "create a directory"),
                     ("rmdir <path>", "remove a directory"),
+                    ("compact [path]", "reclaim unused directory space"),
                     ("cd <path>", "change current directory"),
-                    ("open <path>", "open file"),
+                    (
+                        "open [-c|-x] <path> [n]",
+                        "open file, reserving n blocks per allocation (-c create, -x create new)",
+                    ),
                     ("close <fd>", "close file"),
                     ("read <fd> <size>", "read bytes from file"),
-                    ("write <fd> <string>", "write string to file"),
+                    ("peek <fd> <size>", "read an aligned block without copying"),
+                    ("copyrange <src> <dst> <n>", "copy bytes between open files"),
+                    ("write <fd> [string]", "write string to file"),
                     ("seek <fd> <offset>", "seek to offset"),
+                    ("fdstat <fd>", "display bytes read and written via fd"),
                     ("link <old> <new>", "create hard link"),
+                    ("linktree [-r] <src> <dst>", "hard-link a directory"),
                     ("unlink <path>", "remove file/link"),
+                    ("mv <old> <new>", "move or rename file"),
                     ("symlink <target> <path>", "create symbolic link"),
                     ("truncate <path> <size>", "resize file"),
-                    ("stat <path>", "display file stats"),
-                    ("ls [path]", "list directory"),
+                    ("ftruncate <fd> <size>", "resize opened file"),
+                    ("punch <path> <off> <len>", "deallocate a file range"),
+                    ("stat [-L|-P] <path>", "display file stats"),
+                    ("ls [-alr] [path]", "list directory, -r reversed"),
+                    ("ls --sort <key>", "sort by name, size or node"),
+                    ("nodes", "list allocated nodes"),
+                    ("summary", "count reachable files by type and their usage"),
+                    ("freemap", "display block map as runs"),
+                    ("owner <block>", "display the node using a block"),
+                    ("zombies", "list unlinked nodes kept by open files"),
+                    ("fsck [--repair]", "find and recover orphaned nodes"),
+                    ("compact-nodes", "move nodes to the lowest free ids"),
+                    ("check-nodes", "count reachable and allocated nodes"),
+                    ("dryrun <on|off>", "toggle simulation of changes"),
+                    ("sparse <on|off>", "toggle holes for zero blocks in writes"),
+                    ("safemode <on|off>", "refuse writes that run out of extents"),
+                    ("maxsize <bytes|off>", "limit the size of files"),
+                    ("stdfds <on|off>", "reserve descriptors 0 to 2"),
+                    (
+                        "atime <mode>",
+                        "strict, relatime or noatime updates on read",
+                    ),
+                    ("autoreap <on|off>", "free unlinked files on last close"),
+                    ("reap", "free unlinked files that are closed"),
+                    ("strict <on|off>", "toggle double-free detection"),
+                    ("alloc <first|spread>", "set block allocation strategy"),
+                    ("writethrough <on|off>", "toggle unbuffered block writes"),
+                    ("batch <on|off>", "toggle batching of writes to one fd"),
+                    ("sync", "commit the batched writes"),
+                    ("flushlimit <blocks>", "set blocks buffered before a flush"),
+                    ("commitlog <on|off>", "toggle logging of committed changes"),
+                    ("resolve <path>", "display path resolution steps"),
+                    (
+                        "dirnode <path>",
+                        "display the node id of the parent directory",
+                    ),
+                    (
+                        "fiemap <path> [off len]",
+                        "show where file bytes are stored",
+                    ),
+                    ("links <path>", "list every hard link to a node"),
+                    ("tree [--depth N] [path]", "list the directory tree"),
+                    (
+                        "utimes <path> <at> <mt>",
+                        "set access and modification times",
+                    ),
+                    ("merge [-f] <src> <dst>", "move entries of src into dst"),
+                    ("test -e|-d|-f <path>", "print 0 if the check holds"),
+                    ("newer <since> [path]", "list paths modified since a time"),
+                    ("hash <path>", "display SHA-256 of file contents"),
+                    ("verify <path> <hex>", "compare file contents to SHA-256"),
+                    ("setxattr <path> <k> <v>", "set extended attribute"),
+                    ("getxattr <path> <name>", "display extended attribute"),
+                    ("listxattr <path>", "list extended attributes"),
+                    ("pathcache", "display path cache counters"),
+                    ("time", "display current time and its source"),
+                    ("settime <secs|system>", "set the clock"),
+                    ("advancetime <secs>", "move the clock forward"),
                     ("clear", "clear the screen"),
                     ("exit", "exit the shell"),
                 ];
@@ -223,5 +973,101 @@ fn main() {
             }
             _ => println!("Unknown command: {}", command),
         }
+
+        if let Some(summary) = kernel.take_dry_run_summary() {
+            println!("Dry run, nothing was changed. Would have:");
+            println!("  written {} blocks", summary.blocks_written);
+            println!("  allocated {} blocks", summary.blocks_allocated);
+            println!("  freed {} blocks", summary.blocks_freed);
+            println!("  allocated {} nodes", summary.nodes_allocated);
+            println!("  freed {} nodes", summary.nodes_freed);
+        }
+    }
+
+    commit_batch(&mut kernel, &mut batch);
+
+    if let Err(e) = editor.save_history() {
+        println!("Failed to save history: {}", e);
+    }
+}
+
+/// Parses a SHA-256 digest written as 64 hex digits.
+fn parse_digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Checks that every component of `path` fits into a directory entry,
+/// printing the limit if one doesn't.
+fn check_name_len(path: &str) -> bool {
+    match path.split('/').find(|name| !DirEntryName::is_valid(name)) {
+        Some(name) => {
+            println!(
+                "Error: name '{}' is {} bytes long, the limit is {} bytes",
+                name,
+                name.len(),
+                DirEntryName::MAX_LEN
+            );
+            false
+        }
+        None => true,
+    }
+}
+
+/// Writes to a single file descriptor, buffered by the shell to be committed at once.
+struct WriteBatch {
+    fd: usize,
+    data: Vec<u8>,
+}
+
+/// Commits the pending batch, if there is one, in a single write.
+fn commit_batch(kernel: &mut Kernel, batch: &mut Option<WriteBatch>) {
+    if let Some(batch) = batch.take() {
+        match kernel.write(batch.fd, &batch.data) {
+            Ok(bytes_written) => println!("Committed {} batched bytes.", bytes_written),
+            Err(e) => println!("Error: {:?}", e),
+        }
+    }
+}
+
+/// Prints the entries listed by [Kernel::walk] under `root` with branch characters,
+/// leaving out those nested deeper than `max_depth` levels.
+/// Directories are marked with a trailing '/', symlinks with '@'.
+fn print_tree(root: &str, entries: &[(String, FileType, usize)], max_depth: usize) {
+    let depth = |path: &str| path.matches('/').count();
+    println!("{}", root);
+    // Whether the last printed entry at each level was the last among its siblings
+    let mut last_at: Vec<bool> = Vec::new();
+    for (i, (path, filetype, _)) in entries.iter().enumerate() {
+        let level = depth(path);
+        if level >= max_depth {
+            continue;
+        }
+        let is_last = entries[i + 1..]
+            .iter()
+            .map(|(path, _, _)| depth(path))
+            .take_while(|&d| d >= level)
+            .all(|d| d != level);
+        last_at.truncate(level);
+        let indent: String = last_at
+            .iter()
+            .map(|&last| if last { "    " } else { "│   " })
+            .collect();
+        last_at.push(is_last);
+
+        let branch = if is_last { "└── " } else { "├── " };
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let mark = match filetype {
+            FileType::Dir => "/",
+            FileType::Symlink => "@",
+            FileType::File => "",
+        };
+        println!("{}{}{}{}", indent, branch, name, mark);
     }
 }